@@ -3,11 +3,16 @@
 #[macro_use]
 extern crate criterion;
 
-use criterion::{black_box, Criterion, ParameterizedBenchmark};
-use ift611_project::logger::{Context, Logger};
+use criterion::{black_box, Criterion, ParameterizedBenchmark, Throughput};
+use crossbeam::channel::{self, Sender};
+use ift611_project::logger::{Context, LogLevel, Logger, LoggerBuilder, OverflowPolicy};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::process;
 use std::thread;
 use std::time::Duration;
-use std::fs::File;
 
 #[derive(Copy, Clone)]
 enum TestLogs {
@@ -18,16 +23,20 @@ enum TestLogs {
 }
 
 impl Context for TestLogs {
-    fn context_string(&self) -> String {
-        match self {
-            TestLogs::SmallLog => (0..2).map(|_| "I am a string").collect(),
-            TestLogs::MediumLog => (0..10).map(|_| "I am a string").collect(),
-            TestLogs::BigLog => (0..20).map(|_| "I am a string").collect(),
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        let repeat = match self {
+            TestLogs::SmallLog => 2,
+            TestLogs::MediumLog => 10,
+            TestLogs::BigLog => 20,
             TestLogs::HugeLog => {
                 thread::sleep(Duration::from_millis(5));
-                "I am a string".to_string()
+                1
             }
+        };
+        for _ in 0..repeat {
+            out.write_str("I am a string")?;
         }
+        Ok(())
     }
 }
 
@@ -54,6 +63,15 @@ fn big_log(c: &mut Criterion) {
     });
 }
 
+fn filtered_log(c: &mut Criterion) {
+    c.bench_function("logger-filtered_logs", move |b| {
+        let (logger, _) = LoggerBuilder::new(io::sink())
+            .with_min_level(LogLevel::Info)
+            .start();
+        b.iter(|| logger.debug(black_box(TestLogs::BigLog)))
+    });
+}
+
 fn compare_queue_size(c: &mut Criterion) {
     c.bench(
         "logger-compare_queue_size",
@@ -72,5 +90,120 @@ fn compare_queue_size(c: &mut Criterion) {
     );
 }
 
-criterion_group!(benches, small_log, medium_log, big_log, compare_queue_size);
+fn compare_overflow_policy(c: &mut Criterion) {
+    c.bench(
+        "logger-compare_overflow_policy",
+        ParameterizedBenchmark::new(
+            "block",
+            move |b, _| {
+                let (logger, _) = LoggerBuilder::new(io::sink())
+                    .with_message_queue_size(1)
+                    .with_overflow_policy(OverflowPolicy::Block)
+                    .start();
+                b.iter(|| logger.info(black_box(TestLogs::HugeLog)))
+            },
+            0..1,
+        )
+        .with_function("drop_newest", move |b, _| {
+            let (logger, _) = LoggerBuilder::new(io::sink())
+                .with_message_queue_size(1)
+                .with_overflow_policy(OverflowPolicy::DropNewest)
+                .start();
+            b.iter(|| logger.info(black_box(TestLogs::HugeLog)))
+        })
+        .with_function("drop_oldest", move |b, _| {
+            let (logger, _) = LoggerBuilder::new(io::sink())
+                .with_message_queue_size(1)
+                .with_overflow_policy(OverflowPolicy::DropOldest)
+                .start();
+            b.iter(|| logger.info(black_box(TestLogs::HugeLog)))
+        }),
+    );
+}
+
+/// Notifies every write, to measure when a message reaches the writer.
+struct NotifyingWriter(Sender<()>);
+
+impl Write for NotifyingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let _ = self.0.send(());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+const BATCH_SIZES: [usize; 3] = [1, 16, 64];
+
+fn compare_batching_throughput(c: &mut Criterion) {
+    // a real file, as batching saves writes that `io::sink()` makes free
+    let path = env::temp_dir().join(format!("logger_bench_{}.log", process::id()));
+    let log_path = path.clone();
+    c.bench(
+        "logger-compare_batching_throughput",
+        ParameterizedBenchmark::new(
+            "batch_size",
+            move |b, &batch_size| {
+                let (logger, handle) = LoggerBuilder::new(File::create(&log_path).unwrap())
+                    .with_message_queue_size(QUEUE_SIZE)
+                    .with_batch_size(batch_size)
+                    .start();
+                b.iter(|| {
+                    for _ in 0..QUEUE_SIZE {
+                        logger.info(black_box(TestLogs::SmallLog));
+                    }
+                    handle.flush();
+                })
+            },
+            BATCH_SIZES.to_vec(),
+        )
+        .throughput(|_| Throughput::Elements(QUEUE_SIZE as u32)),
+    );
+    let _ = fs::remove_file(path);
+}
+
+fn compare_batching_latency(c: &mut Criterion) {
+    c.bench(
+        "logger-compare_batching_latency",
+        ParameterizedBenchmark::new(
+            "no_wait",
+            move |b, &batch_size| {
+                let (sender, receiver) = channel::unbounded();
+                let (logger, _) = LoggerBuilder::new(NotifyingWriter(sender))
+                    .with_batch_size(batch_size)
+                    .start();
+                b.iter(|| {
+                    logger.info(black_box(TestLogs::SmallLog));
+                    receiver.recv().unwrap();
+                })
+            },
+            BATCH_SIZES.to_vec(),
+        )
+        .with_function("wait_100us", move |b, &batch_size| {
+            let (sender, receiver) = channel::unbounded();
+            let (logger, _) = LoggerBuilder::new(NotifyingWriter(sender))
+                .with_batch_size(batch_size)
+                .with_batch_max_latency(Duration::from_micros(100))
+                .start();
+            b.iter(|| {
+                logger.info(black_box(TestLogs::SmallLog));
+                receiver.recv().unwrap();
+            })
+        }),
+    );
+}
+
+criterion_group!(
+    benches,
+    small_log,
+    medium_log,
+    big_log,
+    filtered_log,
+    compare_queue_size,
+    compare_overflow_policy,
+    compare_batching_throughput,
+    compare_batching_latency
+);
 criterion_main!(benches);
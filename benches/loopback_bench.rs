@@ -0,0 +1,169 @@
+// Author: Karim Elmougi
+
+#[macro_use]
+extern crate criterion;
+
+use chrono::Utc;
+use circular_queue::CircularQueue;
+use criterion::{Criterion, ParameterizedBenchmark, Throughput};
+use crossbeam::channel::{self, Receiver};
+use ift611_project::client::*;
+use ift611_project::datagen::MarketDataGenerator;
+use ift611_project::server::{ReplayMode, ServerBuilder, ServerHandle};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// in microseconds
+const PERIODS: [u64; 3] = [1000, 100, 20];
+const RECORDS_PER_ITERATION: usize = 10;
+const RECORD_COUNT: usize = 10_000;
+
+/// A server on an ephemeral port and its subscribed clients, each running the
+/// dummy strategy on every record pushed to it.
+struct Loopback {
+    server: Option<ServerHandle>,
+    client_count: usize,
+    period: u64,
+    decisions: Receiver<()>,
+    // shared by every client
+    stats: Arc<LatencyStats>,
+}
+
+impl Loopback {
+    fn start(data_file: &str, period: u64, client_count: usize) -> Self {
+        let server = ServerBuilder::new(data_file)
+            .with_http_port(0)
+            .with_period(period)
+            .with_replay_mode(ReplayMode::Loop)
+            .with_max_subscriber_count(client_count)
+            .with_log_writer(io::sink())
+            .build_and_start()
+            .unwrap();
+        let url = format!("http://{}", server.http_addr());
+
+        let (sender, decisions) = channel::unbounded();
+        let stats = Arc::new(LatencyStats::new());
+        for _ in 0..client_count {
+            let mut connection = TradingPair::BTCUSD.subscribe(&url).unwrap();
+            let stats = stats.clone();
+            let sender = sender.clone();
+
+            thread::spawn(move || {
+                let strategy = TradingStrategy::from("dummy");
+                let mut queue = CircularQueue::with_capacity(100);
+                while let Some(timed) = connection.next_timed() {
+                    queue.push(timed.record);
+                    let _ = strategy.make_decision(&queue);
+                    stats.record(&timed, Utc::now());
+                    if sender.send(()).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Loopback {
+            server: Some(server),
+            client_count,
+            period,
+            decisions,
+            stats,
+        }
+    }
+
+    /// Waits for every client to decide on `count` more records.
+    fn receive(&self, count: usize) {
+        // decisions made between two iterations don't count
+        for _ in self.decisions.try_iter() {}
+        for _ in 0..count * self.client_count {
+            self.decisions
+                .recv_timeout(Duration::from_secs(5))
+                .expect("a client fell behind and was dropped by the server");
+        }
+    }
+}
+
+impl Drop for Loopback {
+    /// Criterion only reports the time taken by iterations, the latency
+    /// distribution of every decision is printed and saved to
+    /// `target/loopback/` instead.
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.shutdown();
+        }
+
+        let stats = &*self.stats;
+        let report_dir = Path::new("target").join("loopback");
+        let report_path = report_dir.join(format!(
+            "{}_clients_{}us.json",
+            self.client_count, self.period
+        ));
+        let _ = fs::create_dir_all(&report_dir);
+        if let Ok(file) = File::create(&report_path) {
+            let _ = serde_json::to_writer_pretty(file, stats);
+        }
+        println!(
+            "loopback: {} client(s), period {}us",
+            self.client_count, self.period
+        );
+        for &stage in LatencyStage::ALL.iter() {
+            if let Some(percentiles) = stats.percentiles(stage) {
+                println!("  {} latency: {}", stage, percentiles);
+            }
+        }
+    }
+}
+
+/// Synthetic records for the servers to replay, removed once the benchmark
+/// is done with them.
+struct DataFile(PathBuf);
+
+impl DataFile {
+    fn new(client_count: usize) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "loopback_bench_{}_{}.csv",
+            client_count,
+            std::process::id()
+        ));
+        MarketDataGenerator::new(0)
+            .write_csv(File::create(&path).unwrap(), RECORD_COUNT)
+            .unwrap();
+        DataFile(path)
+    }
+}
+
+impl Drop for DataFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+fn loopback(client_count: usize) -> impl FnMut(&mut criterion::Bencher, &u64) {
+    let data_file = DataFile::new(client_count);
+    let mut loopbacks = HashMap::new();
+    move |b, &period| {
+        // starting a server for every sample would measure its startup
+        let loopback = loopbacks.entry(period).or_insert_with(|| {
+            Loopback::start(data_file.0.to_str().unwrap(), period, client_count)
+        });
+        b.iter(|| loopback.receive(RECORDS_PER_ITERATION))
+    }
+}
+
+fn loopback_benchmark(c: &mut Criterion) {
+    c.bench(
+        "loopback",
+        ParameterizedBenchmark::new("1_client", loopback(1), PERIODS.to_vec())
+            .with_function("8_clients", loopback(8))
+            .sample_size(10)
+            .throughput(|_| Throughput::Elements(RECORDS_PER_ITERATION as u32)),
+    );
+}
+
+criterion_group!(benches, loopback_benchmark);
+criterion_main!(benches);
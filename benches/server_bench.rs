@@ -3,16 +3,17 @@
 #[macro_use]
 extern crate criterion;
 
+use ift611_project::client::Record;
+use ift611_project::clock::RealClock;
 use ift611_project::logger::Logger;
-use ift611_project::server::{push_data};
+use ift611_project::server::{push_data, Replay, ReplayMode, ServerMetrics, Subscriber};
 use criterion::Criterion;
 use std::net::{Ipv4Addr, TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
+use std::io;
 use std::thread;
-use std::time::Instant;
-use std::fs::File;
 
-fn start_dummy_server(streams: Arc<Mutex<Vec<TcpStream>>>) -> u16 {
+fn start_dummy_server(streams: Arc<Mutex<Vec<Subscriber>>>) -> u16 {
     let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
 
     let push_port = listener.local_addr().unwrap().port();
@@ -20,7 +21,7 @@ fn start_dummy_server(streams: Arc<Mutex<Vec<TcpStream>>>) -> u16 {
     thread::spawn(move || {
         for stream in listener.incoming() {
             if let Ok(stream) = stream {
-                streams.lock().unwrap().push(stream);
+                streams.lock().unwrap().push(Subscriber::new(stream, vec![0]));
             }
         }
     });
@@ -28,12 +29,12 @@ fn start_dummy_server(streams: Arc<Mutex<Vec<TcpStream>>>) -> u16 {
     push_port
 }
 
-fn populate_streams(port: u16, streams: Arc<Mutex<Vec<TcpStream>>>) {
+fn populate_streams(port: u16, streams: Arc<Mutex<Vec<Subscriber>>>) {
     let mut streams = streams.lock().unwrap();
     for _ in 0..100 {
         let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
         stream.set_nonblocking(true).unwrap();
-        streams.push(stream);
+        streams.push(Subscriber::new(stream, vec![0]));
     }
 }
 
@@ -42,12 +43,19 @@ fn push_data_benchmark(c: &mut Criterion) {
     let port = start_dummy_server(streams.clone());
     populate_streams(port, streams.clone());
 
-    let logger = Logger::start(File::create("test_log.csv").unwrap(), 100);
-    let data: Vec<String> = (0..1000).map(|_| "I am a string!".to_string()).collect();
-    let start_time = Instant::now();
+    let logger = Logger::start(io::sink(), 100);
+    let data: Vec<Record> = (0..1000)
+        .map(|i| Record {
+            timestamp: 1_546_300_800_000 + i * 60_000,
+            ..Record::default()
+        })
+        .collect();
+    let replays = vec![Replay::new("BTCUSD", data, 100_000, ReplayMode::Loop, Arc::new(RealClock))];
+
+    let metrics = ServerMetrics::new();
 
     c.bench_function("server-push_data", move |b| {
-        b.iter(|| push_data(&logger, &streams, start_time, 100_000, &data))
+        b.iter(|| push_data(&logger, &streams, &replays, &metrics))
     });
 }
 
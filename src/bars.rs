@@ -0,0 +1,151 @@
+// Author: Karim Elmougi
+
+use crate::client::Record;
+use circular_queue::CircularQueue;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How records are grouped into bars.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BarSpec {
+    /// Bars of a fixed duration, aligned on the Unix epoch, e.g. 5 minutes.
+    Time(Duration),
+    /// Bars of a fixed number of records.
+    Ticks(usize),
+    /// Bars closed once their volume reaches the threshold.
+    Volume(f64),
+}
+
+/// Parses `30s`, `5m` or `1h` time bars, `100t` tick bars and `50v` volume bars.
+impl FromStr for BarSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid bar spec: {}", s);
+        if s.len() < 2 || !s.is_char_boundary(s.len() - 1) {
+            return Err(invalid());
+        }
+        let (amount, unit) = s.split_at(s.len() - 1);
+        let spec = match unit {
+            "s" | "m" | "h" => {
+                let amount: u64 = amount.parse().map_err(|_| invalid())?;
+                let unit_secs = match unit {
+                    "s" => 1,
+                    "m" => 60,
+                    _ => 3600,
+                };
+                // bars start at timestamps in milliseconds, which are i64s
+                let secs = amount
+                    .checked_mul(unit_secs)
+                    .filter(|&secs| secs <= i64::MAX as u64 / 1000)
+                    .ok_or_else(invalid)?;
+                BarSpec::Time(Duration::from_secs(secs))
+            }
+            "t" => BarSpec::Ticks(amount.parse().map_err(|_| invalid())?),
+            "v" => BarSpec::Volume(amount.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+        match spec {
+            BarSpec::Time(duration) if duration.as_millis() == 0 => Err(invalid()),
+            BarSpec::Ticks(0) => Err(invalid()),
+            BarSpec::Volume(volume) if volume.is_nan() || volume <= 0.0 => Err(invalid()),
+            spec => Ok(spec),
+        }
+    }
+}
+
+/// Builds bars of a higher timeframe from a stream of records. A bar is a
+/// `Record` whose timestamp is the start of the bar.
+pub struct Resampler {
+    spec: BarSpec,
+    bar: Option<Record>,
+    // records in the bar
+    tick_count: usize,
+}
+
+impl Resampler {
+    pub fn new(spec: BarSpec) -> Self {
+        Resampler {
+            spec,
+            bar: None,
+            tick_count: 0,
+        }
+    }
+
+    /// Adds a record, returning the bar it completed, if any. A time bar is
+    /// only completed by the first record past its end, and periods without
+    /// records have no bars.
+    pub fn push(&mut self, record: &Record) -> Option<Record> {
+        let mut completed = None;
+        if let (BarSpec::Time(duration), Some(bar)) = (self.spec, &self.bar) {
+            if bar_start(record.timestamp, duration) != bar.timestamp {
+                completed = self.bar.take();
+            }
+        }
+
+        match &mut self.bar {
+            Some(bar) => {
+                bar.high = bar.high.max(record.high);
+                bar.low = bar.low.min(record.low);
+                bar.close = record.close;
+                bar.volume += record.volume;
+                self.tick_count += 1;
+            }
+            None => {
+                let mut bar = *record;
+                if let BarSpec::Time(duration) = self.spec {
+                    bar.timestamp = bar_start(record.timestamp, duration);
+                }
+                self.bar = Some(bar);
+                self.tick_count = 1;
+            }
+        }
+
+        let is_full = match (self.spec, &self.bar) {
+            (BarSpec::Ticks(count), _) => self.tick_count >= count,
+            (BarSpec::Volume(volume), Some(bar)) => bar.volume >= volume,
+            _ => false,
+        };
+        if is_full {
+            completed = self.bar.take();
+        }
+        completed
+    }
+
+    /// The bar being built, if a record was pushed since the last one completed.
+    pub fn partial(&self) -> Option<&Record> {
+        self.bar.as_ref()
+    }
+}
+
+fn bar_start(timestamp: i64, duration: Duration) -> i64 {
+    let millis = duration.as_millis() as i64;
+    timestamp - timestamp.rem_euclid(millis)
+}
+
+/// The latest bars of a resampled series, which strategies decide on the same
+/// way as on raw records.
+pub struct BarSeries {
+    resampler: Resampler,
+    bars: CircularQueue<Record>,
+}
+
+impl BarSeries {
+    pub fn new(spec: BarSpec, capacity: usize) -> Self {
+        BarSeries {
+            resampler: Resampler::new(spec),
+            bars: CircularQueue::with_capacity(capacity),
+        }
+    }
+
+    /// Adds a record, returning the series when it completed a bar.
+    pub fn push(&mut self, record: &Record) -> Option<&CircularQueue<Record>> {
+        let bar = self.resampler.push(record)?;
+        self.bars.push(bar);
+        Some(&self.bars)
+    }
+
+    pub fn bars(&self) -> &CircularQueue<Record> {
+        &self.bars
+    }
+}
@@ -1,11 +1,21 @@
 // Author: Karim Elmougi
 
-use circular_queue::CircularQueue;
-use clap::{App, Arg};
+use chrono::Utc;
+use clap::{value_t_or_exit, values_t_or_exit, App, Arg};
+use crossbeam::channel;
+use ift611_project::bars::{BarSeries, BarSpec};
 use ift611_project::client::*;
-use ift611_project::logger::Logger;
-use std::io::stdout;
+use ift611_project::logger::{
+    LogDestination, LogFormat, Logger, LoggerBuilder, OverflowPolicy, WriteMode,
+};
+use std::collections::HashMap;
 use std::fs::File;
+use std::process;
+use std::sync::Arc;
+use std::thread;
+
+// decisions between two latency reports in the log
+const LATENCY_REPORT_PERIOD: usize = 1000;
 
 fn main() {
     let matches = App::new("HFT Client")
@@ -17,6 +27,24 @@ fn main() {
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("pairs")
+                .help("Sets the trading pairs to subscribe to, on a single connection")
+                .long("pairs")
+                .takes_value(true)
+                .value_name("PAIRS")
+                .use_delimiter(true)
+                .possible_values(&["BTCUSD", "ETHUSD", "LTCUSD"]),
+        )
+        .arg(
+            Arg::with_name("bars")
+                .help(
+                    "Makes the strategy decide on bars, e.g. 5m, 1h, 100t (ticks) or 50v (volume)",
+                )
+                .long("bars")
+                .takes_value(true)
+                .value_name("SPEC"),
+        )
         .arg(
             Arg::with_name("strategy")
                 .help("Sets the trading strategy to use")
@@ -33,22 +61,155 @@ fn main() {
                 .value_name("WRITER")
                 .possible_values(&["file", "stdout"]),
         )
+        .arg(
+            Arg::with_name("log-file")
+                .help("Sets the file decisions are written to when using the file writer")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("log-append")
+                .help("Appends to the log file instead of truncating it")
+                .long("log-append"),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .help("Sets the format of the log messages")
+                .long("log-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["plain", "csv", "json_lines", "binary"]),
+        )
+        .arg(
+            Arg::with_name("log-overflow")
+                .help("Sets what happens to log messages when the logger's queue is full")
+                .long("log-overflow")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&["block", "drop_newest", "drop_oldest", "drop_and_report"]),
+        )
+        .arg(
+            Arg::with_name("latency-report")
+                .help("Writes the latency snapshots of every stage to a JSON file on exit")
+                .long("latency-report")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
         .get_matches();
 
     let url = matches.value_of("URL").unwrap();
+    let pairs = if matches.is_present("pairs") {
+        values_t_or_exit!(matches, "pairs", TradingPair)
+    } else {
+        vec![TradingPair::BTCUSD]
+    };
+    let bar_spec = if matches.is_present("bars") {
+        Some(value_t_or_exit!(matches, "bars", BarSpec))
+    } else {
+        None
+    };
     let strategy: TradingStrategy = matches.value_of("strategy").unwrap_or("dummy").into();
 
-    let logger = match matches.value_of("writer").unwrap_or("stdout") {
-        "file" => Logger::start(File::create("decisions_log.csv").unwrap(), 100),
-        "stdout" => Logger::start(stdout(), 100),
-        _ => Logger::start(stdout(), 100),
+    let destination = match matches.value_of("writer").unwrap_or("stdout") {
+        "file" => LogDestination::File(
+            matches
+                .value_of("log-file")
+                .unwrap_or("decisions_log.csv")
+                .to_owned(),
+            if matches.is_present("log-append") {
+                WriteMode::Append
+            } else {
+                WriteMode::Truncate
+            },
+        ),
+        _ => LogDestination::Stdout,
     };
+    let overflow_policy = if matches.is_present("log-overflow") {
+        value_t_or_exit!(matches, "log-overflow", OverflowPolicy)
+    } else {
+        OverflowPolicy::Block
+    };
+    let format = if matches.is_present("log-format") {
+        value_t_or_exit!(matches, "log-format", LogFormat)
+    } else {
+        LogFormat::Plain
+    };
+    let (logger, logger_handle) = LoggerBuilder::new(destination.open().unwrap())
+        .with_overflow_policy(overflow_policy)
+        .with_format(format)
+        .start();
+    // messages from dependencies such as reqwest end up with the decisions
+    logger_handle.log_bridge().install().unwrap();
+
+    let mut connection = TradingPair::subscribe_many(url, &pairs).unwrap();
+    let stats = Arc::new(LatencyStats::new());
+    let (stop_sender, stop_receiver) = channel::bounded(2);
+
+    let ctrlc_sender = stop_sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = ctrlc_sender.try_send(());
+    })
+    .expect("could not set the SIGINT/SIGTERM handler");
 
-    let mut queue = CircularQueue::with_capacity(100);
+    {
+        let logger = logger.clone();
+        let stats = Arc::clone(&stats);
+        thread::spawn(move || {
+            let mut queues = PairQueues::with_capacity(100);
+            let mut bar_series = HashMap::new();
+            let mut decision_count = 0;
+
+            while let Some(timed) = connection.next_timed() {
+                let decision = match bar_spec {
+                    // no decision until a bar is completed
+                    Some(spec) => match bar_series
+                        .entry(timed.pair)
+                        .or_insert_with(|| BarSeries::new(spec, 100))
+                        .push(&timed.record)
+                    {
+                        Some(bars) => strategy.make_decision(bars),
+                        None => continue,
+                    },
+                    None => strategy.make_decision(queues.push(timed.pair, timed.record)),
+                };
+                let decided_at = Utc::now();
+                logger.info(decision);
+
+                stats.record(&timed, decided_at);
+                decision_count += 1;
+                if decision_count % LATENCY_REPORT_PERIOD == 0 {
+                    log_latencies(&logger, &stats);
+                }
+            }
+            let _ = stop_sender.try_send(());
+        });
+    }
+
+    let _ = stop_receiver.recv();
+    for &stage in LatencyStage::ALL.iter() {
+        if let Some(percentiles) = stats.percentiles(stage) {
+            eprintln!("{} latency: {}", stage, percentiles);
+        }
+    }
+    log_latencies(&logger, &stats);
+    logger_handle.shutdown();
+
+    if let Some(path) = matches.value_of("latency-report") {
+        let result = File::create(path)
+            .map_err(serde_json::Error::io)
+            .and_then(|file| serde_json::to_writer_pretty(file, &*stats));
+        if let Err(err) = result {
+            eprintln!("could not write the latency report: {}", err);
+            process::exit(1);
+        }
+    }
+}
 
-    for record in TradingPair::BTCUSD.subscribe(url).unwrap() {
-        queue.push(record);
-        let decision = strategy.make_decision(&queue);
-        logger.info(decision);
+fn log_latencies(logger: &Logger<DecisionLogs>, stats: &LatencyStats) {
+    for &stage in LatencyStage::ALL.iter() {
+        if let Some(percentiles) = stats.percentiles(stage) {
+            logger.info(DecisionLogs::Latency(stage, percentiles));
+        }
     }
 }
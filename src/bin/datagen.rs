@@ -0,0 +1,157 @@
+// Author: Karim Elmougi
+
+use chrono::{DateTime, Utc};
+use clap::{value_t_or_exit, App, Arg};
+use ift611_project::datagen::{MarketDataGenerator, PriceModel};
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+use std::time::Duration;
+
+// in seconds
+const MAX_INTERVAL: f64 = 365.0 * 24.0 * 3600.0;
+
+fn main() {
+    let matches = App::new("HFT Data Generator")
+        .version("0.1.0")
+        .author("Karim Elmougi <karim@elmougi.dev>")
+        .about("Generates reproducible synthetic market data in the CSV format replayed by the server")
+        .arg(
+            Arg::with_name("OUTPUT")
+                .help("Sets the CSV file to write, - for the standard output")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("model")
+                .help("Sets how prices move: geometric Brownian motion, mean-reverting or jump-diffusion")
+                .long("model")
+                .takes_value(true)
+                .value_name("MODEL")
+                .possible_values(&["gbm", "ou", "jump"])
+                .default_value("gbm"),
+        )
+        .arg(number_arg("count", "Sets the amount of records to generate").default_value("10000"))
+        .arg(number_arg("seed", "Sets the seed, the same seed gives the same data").default_value("0"))
+        .arg(
+            Arg::with_name("symbol")
+                .help("Sets the symbol of the records")
+                .long("symbol")
+                .takes_value(true)
+                .value_name("SYMBOL")
+                .default_value("BTCUSD"),
+        )
+        .arg(
+            Arg::with_name("start")
+                .help("Sets the time of the first record, in RFC 3339")
+                .long("start")
+                .takes_value(true)
+                .value_name("TIME")
+                .default_value("2019-01-01T00:00:00Z"),
+        )
+        .arg(number_arg("interval", "Sets the seconds between two records").default_value("60"))
+        .arg(number_arg("start-price", "Sets the price of the first record").default_value("3600"))
+        .arg(number_arg("drift", "Sets the annualized drift, not used by the ou model").default_value("0"))
+        .arg(number_arg("volatility", "Sets the annualized volatility").default_value("0.8"))
+        .arg(number_arg("volume", "Sets the mean volume of a record").default_value("50"))
+        .arg(number_arg(
+            "mean-reversion",
+            "Sets the annualized speed at which the ou model goes back to its mean",
+        ).default_value("50"))
+        .arg(number_arg(
+            "mean",
+            "Sets the price the ou model goes back to, the start price by default",
+        ))
+        .arg(number_arg(
+            "jump-intensity",
+            "Sets the expected amount of jumps per year of the jump model",
+        ).default_value("100"))
+        .arg(number_arg("jump-mean", "Sets the mean of the logarithm of the jumps").default_value("0"))
+        .arg(number_arg(
+            "jump-volatility",
+            "Sets the standard deviation of the logarithm of the jumps",
+        ).default_value("0.02"))
+        .get_matches();
+
+    let start_price = value_t_or_exit!(matches, "start-price", f64);
+    let model = match matches.value_of("model").unwrap() {
+        "ou" => {
+            if matches.occurrences_of("drift") > 0 {
+                eprintln!("the ou model has no drift, it goes back to its mean");
+                process::exit(1);
+            }
+            let mean = if matches.is_present("mean") {
+                value_t_or_exit!(matches, "mean", f64)
+            } else {
+                start_price
+            };
+            if mean.is_nan() || mean <= 0.0 {
+                eprintln!("the mean must be positive");
+                process::exit(1);
+            }
+            PriceModel::MeanReverting {
+                speed: value_t_or_exit!(matches, "mean-reversion", f64),
+                mean,
+            }
+        }
+        "jump" => PriceModel::JumpDiffusion {
+            intensity: value_t_or_exit!(matches, "jump-intensity", f64),
+            jump_mean: value_t_or_exit!(matches, "jump-mean", f64),
+            jump_volatility: value_t_or_exit!(matches, "jump-volatility", f64),
+        },
+        _ => PriceModel::GeometricBrownian,
+    };
+    let start = DateTime::parse_from_rfc3339(matches.value_of("start").unwrap())
+        .unwrap_or_else(|err| {
+            eprintln!("invalid start time: {}", err);
+            process::exit(1);
+        })
+        .with_timezone(&Utc);
+    let interval = value_t_or_exit!(matches, "interval", f64);
+    // timestamps are in milliseconds
+    if !(0.001..=MAX_INTERVAL).contains(&interval) {
+        eprintln!("the interval must be between a millisecond and a year");
+        process::exit(1);
+    }
+    if start_price.is_nan() || start_price <= 0.0 {
+        eprintln!("the start price must be positive");
+        process::exit(1);
+    }
+    let volatility = value_t_or_exit!(matches, "volatility", f64);
+    let volume = value_t_or_exit!(matches, "volume", f64);
+    // `validate` would reject the data otherwise
+    if !(volatility >= 0.0 && volume >= 0.0) {
+        eprintln!("the volatility and the volume must not be negative");
+        process::exit(1);
+    }
+
+    let mut generator = MarketDataGenerator::new(value_t_or_exit!(matches, "seed", u64))
+        .with_symbol(matches.value_of("symbol").unwrap())
+        .with_model(model)
+        .with_start_price(start_price)
+        .with_drift(value_t_or_exit!(matches, "drift", f64))
+        .with_volatility(volatility)
+        .with_mean_volume(volume)
+        .with_interval(Duration::from_secs_f64(interval))
+        .with_start_time(start);
+
+    let output: Box<dyn Write> = match matches.value_of("OUTPUT").unwrap() {
+        "-" => Box::new(io::stdout()),
+        path => Box::new(File::create(path).unwrap_or_else(|err| {
+            eprintln!("could not create {}: {}", path, err);
+            process::exit(1);
+        })),
+    };
+    if let Err(err) = generator.write_csv(output, value_t_or_exit!(matches, "count", usize)) {
+        eprintln!("could not write the data: {}", err);
+        process::exit(1);
+    }
+}
+
+fn number_arg<'a>(name: &'a str, help: &'a str) -> Arg<'a, 'a> {
+    Arg::with_name(name)
+        .help(help)
+        .long(name)
+        .takes_value(true)
+        .value_name("NUMBER")
+}
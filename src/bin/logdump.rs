@@ -0,0 +1,134 @@
+// Author: Karim Elmougi
+
+use chrono::SecondsFormat;
+use clap::{App, Arg};
+use ift611_project::client::DecisionLogs;
+use ift611_project::logger::{BinaryRecord, Context};
+use ift611_project::server::ServerLogs;
+use serde::Serialize;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::process;
+
+/// Which binary's log is decoded, as the event ids of the client and the
+/// server overlap.
+#[derive(Copy, Clone)]
+enum Source {
+    Client,
+    Server,
+}
+
+impl Source {
+    /// The message of a known event, as written by the text formats.
+    fn message(self, record: &BinaryRecord) -> Option<String> {
+        let mut message = String::new();
+        let result = match self {
+            Source::Client => DecisionLogs::from_binary(record.event_id, &record.payload)?
+                .write_context(&mut message),
+            Source::Server => ServerLogs::from_binary(record.event_id, &record.payload)?
+                .write_context(&mut message),
+        };
+        result.ok().map(|()| message)
+    }
+}
+
+#[derive(Serialize)]
+struct DumpedRecord {
+    timestamp: String,
+    level: String,
+    event_id: u16,
+    message: String,
+    /// In hexadecimal, only for unknown events.
+    payload: String,
+}
+
+impl DumpedRecord {
+    fn new(record: BinaryRecord, source: Source) -> Self {
+        let message = source.message(&record);
+        let payload = match message {
+            Some(_) => String::new(),
+            None => record
+                .payload
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        };
+        DumpedRecord {
+            timestamp: record.timestamp.to_rfc3339_opts(SecondsFormat::Nanos, true),
+            level: record.level.to_string(),
+            event_id: record.event_id,
+            message: message.unwrap_or_default(),
+            payload,
+        }
+    }
+}
+
+fn main() {
+    let matches = App::new("HFT Log Dump")
+        .version("0.1.0")
+        .author("Karim Elmougi <karim@elmougi.dev>")
+        .about("Decodes binary logs into CSV or JSON lines, written to the standard output")
+        .arg(
+            Arg::with_name("INPUT")
+                .help("Sets the binary log file to decode, - for the standard input")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("format")
+                .help("Sets the output format")
+                .long("format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["csv", "json"]),
+        )
+        .arg(
+            Arg::with_name("source")
+                .help("Sets which binary wrote the log, to decode its events")
+                .long("source")
+                .takes_value(true)
+                .value_name("SOURCE")
+                .possible_values(&["client", "server"])
+                .default_value("client"),
+        )
+        .get_matches();
+
+    let input: Box<dyn Read> = match matches.value_of("INPUT").unwrap() {
+        "-" => Box::new(io::stdin()),
+        path => Box::new(File::open(path).unwrap_or_else(|err| {
+            eprintln!("could not open {}: {}", path, err);
+            process::exit(1);
+        })),
+    };
+    let source = match matches.value_of("source").unwrap() {
+        "server" => Source::Server,
+        _ => Source::Client,
+    };
+    let result = match matches.value_of("format").unwrap_or("csv") {
+        "json" => dump_json(BufReader::new(input), source),
+        _ => dump_csv(BufReader::new(input), source),
+    };
+
+    if let Err(err) = result {
+        eprintln!("could not decode the log: {}", err);
+        process::exit(1);
+    }
+}
+
+fn dump_csv<R: Read>(mut input: R, source: Source) -> io::Result<()> {
+    let mut writer = csv::Writer::from_writer(io::stdout());
+    while let Some(record) = BinaryRecord::read_from(&mut input)? {
+        writer.serialize(DumpedRecord::new(record, source))?;
+    }
+    writer.flush()
+}
+
+fn dump_json<R: Read>(mut input: R, source: Source) -> io::Result<()> {
+    let stdout = io::stdout();
+    let mut writer = BufWriter::new(stdout.lock());
+    while let Some(record) = BinaryRecord::read_from(&mut input)? {
+        serde_json::to_writer(&mut writer, &DumpedRecord::new(record, source))?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
@@ -1,8 +1,13 @@
 // Author: Karim Elmougi
 
-use ift611_project::server::ServerBuilder;
+use ift611_project::config::{InstrumentConfig, ServerConfig};
+use ift611_project::logger::{LogFormat, LogLevel, OverflowPolicy, WriteMode};
+use ift611_project::server::ReplayMode;
 
-use clap::{App, Arg};
+use clap::{value_t_or_exit, App, Arg, ArgMatches};
+use crossbeam::channel;
+use std::net::{IpAddr, Ipv6Addr};
+use std::process;
 
 fn main() {
     let matches = App::new("HFT Server")
@@ -11,9 +16,36 @@ fn main() {
         .arg(
             Arg::with_name("INPUT")
                 .help("Sets the input data file to use")
-                .required(true)
+                .required_unless("config")
                 .index(1),
         )
+        .arg(
+            Arg::with_name("config")
+                .help("Reads the server configuration from a TOML file, flags override its values")
+                .short("c")
+                .long("config")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .help("Prints the resulting configuration as TOML and exits")
+                .long("print-config"),
+        )
+        .arg(
+            Arg::with_name("symbol")
+                .help("Sets the symbol of the instrument to serve")
+                .long("symbol")
+                .takes_value(true)
+                .value_name("SYMBOL"),
+        )
+        .arg(
+            Arg::with_name("max-records")
+                .help("Sets the maximum number of records to load from the input data file")
+                .long("max-records")
+                .takes_value(true)
+                .value_name("AMOUNT"),
+        )
         .arg(
             Arg::with_name("period")
                 .help("Sets the period in μs with which to push data to subscribers")
@@ -21,6 +53,14 @@ fn main() {
                 .takes_value(true)
                 .value_name("PERIOD"),
         )
+        .arg(
+            Arg::with_name("replay-mode")
+                .help("Sets whether the data is replayed once or in a loop")
+                .long("replay-mode")
+                .takes_value(true)
+                .value_name("MODE")
+                .possible_values(&["once", "loop"]),
+        )
         .arg(
             Arg::with_name("max-subscribers")
                 .help("Sets the maximum number of subscribers at one time")
@@ -28,6 +68,47 @@ fn main() {
                 .takes_value(true)
                 .value_name("VALUE"),
         )
+        .arg(
+            Arg::with_name("log-file")
+                .help("Sets the file the server logs are written to")
+                .long("log-file")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .arg(
+            Arg::with_name("log-append")
+                .help("Appends to the log file instead of truncating it, --log-append=false truncates it")
+                .long("log-append")
+                .takes_value(true)
+                .value_name("BOOL")
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["true", "false"]),
+        )
+        .arg(
+            Arg::with_name("log-overflow")
+                .help("Sets what happens to log messages when the logger's queue is full")
+                .long("log-overflow")
+                .takes_value(true)
+                .value_name("POLICY")
+                .possible_values(&["block", "drop_newest", "drop_oldest", "drop_and_report"]),
+        )
+        .arg(
+            Arg::with_name("log-level")
+                .help("Sets the minimum level of the messages written to the log file")
+                .long("log-level")
+                .takes_value(true)
+                .value_name("LEVEL")
+                .possible_values(&["trace", "debug", "info", "warning", "error"]),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .help("Sets the format of the log messages")
+                .long("log-format")
+                .takes_value(true)
+                .value_name("FORMAT")
+                .possible_values(&["plain", "csv", "json_lines", "binary"]),
+        )
         .arg(
             Arg::with_name("log-queue-size")
                 .help("Sets the size of the logger's message queue")
@@ -43,40 +124,143 @@ fn main() {
                 .takes_value(true)
                 .value_name("PORT"),
         )
+        .arg(
+            Arg::with_name("bind")
+                .help("Sets the address the http and push servers bind to")
+                .long("bind")
+                .takes_value(true)
+                .value_name("ADDRESS"),
+        )
+        .arg(
+            Arg::with_name("push-port")
+                .help("Sets the port to push data to subscribers from, random if not set")
+                .long("push-port")
+                .takes_value(true)
+                .value_name("PORT"),
+        )
+        .arg(
+            Arg::with_name("ipv6")
+                .help("Binds to the IPv6 loopback address instead of the IPv4 one")
+                .long("ipv6")
+                .conflicts_with("bind"),
+        )
         .get_matches();
 
-    let mut builder = ServerBuilder::new(matches.value_of("INPUT").unwrap());
+    let mut config = match matches.value_of("config") {
+        Some(path) => ServerConfig::from_file(path).unwrap_or_else(|err| exit_with_error(&err)),
+        None => ServerConfig::default(),
+    };
+
+    apply_flags(&mut config, &matches);
+
+    if let Err(err) = config.validate() {
+        exit_with_error(&err);
+    }
+
+    if matches.is_present("print-config") {
+        print!("{}", config.to_toml_string());
+        return;
+    }
+
+    let server = config
+        .to_builder()
+        .unwrap_or_else(|err| exit_with_error(&err))
+        .build_and_start()
+        .unwrap_or_else(|err| exit_with_error(&err));
+    server.log_bridge().install().unwrap();
+
+    let (stop_sender, stop_receiver) = channel::bounded(1);
+    ctrlc::set_handler(move || {
+        let _ = stop_sender.try_send(());
+    })
+    .expect("could not set the SIGINT/SIGTERM handler");
+
+    let _ = stop_receiver.recv();
+    server.shutdown();
+}
 
-    if let Some(port) = matches.value_of("port") {
-        builder = builder.with_http_port(
-            port.parse()
-                .unwrap_or_else(|_| panic!("invalid port value: {}", port)),
-        );
+fn apply_flags(config: &mut ServerConfig, matches: &ArgMatches) {
+    if config.instruments.is_empty() {
+        config.instruments.push(InstrumentConfig {
+            symbol: "BTCUSD".to_owned(),
+            data_file: String::new(),
+            max_records_amount: None,
+        });
     }
+    let instrument = &mut config.instruments[0];
 
-    if let Some(period) = matches.value_of("period") {
-        builder = builder.with_period(
-            period
-                .parse()
-                .unwrap_or_else(|_| panic!("invalid period value: {}", period)),
-        );
+    if let Some(input) = matches.value_of("INPUT") {
+        instrument.data_file = input.to_owned();
     }
 
-    if let Some(max_sub) = matches.value_of("max-subscribers") {
-        builder = builder.with_max_subscriber_count(
-            max_sub
-                .parse()
-                .unwrap_or_else(|_| panic!("invalid max-subscribers value: {}", max_sub)),
-        );
+    if let Some(symbol) = matches.value_of("symbol") {
+        instrument.symbol = symbol.to_owned();
     }
 
-    if let Some(log_queue_size) = matches.value_of("log-queue-size") {
-        builder = builder.with_logger_queue_size(
-            log_queue_size
-                .parse()
-                .unwrap_or_else(|_| panic!("invalid log-queue-size value: {}", log_queue_size)),
-        );
+    if matches.is_present("max-records") {
+        instrument.max_records_amount = Some(value_t_or_exit!(matches, "max-records", usize));
     }
 
-    builder.build_and_start().join().unwrap();
+    if matches.is_present("port") {
+        config.http_port = value_t_or_exit!(matches, "port", u16);
+    }
+
+    if matches.is_present("bind") {
+        config.bind_address = value_t_or_exit!(matches, "bind", IpAddr);
+    }
+
+    if matches.is_present("ipv6") {
+        config.bind_address = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    }
+
+    if matches.is_present("push-port") {
+        config.push_port = value_t_or_exit!(matches, "push-port", u16);
+    }
+
+    if matches.is_present("period") {
+        config.period = value_t_or_exit!(matches, "period", u64);
+    }
+
+    if let Some(replay_mode) = matches.value_of("replay-mode") {
+        config.replay_mode = match replay_mode {
+            "loop" => ReplayMode::Loop,
+            _ => ReplayMode::Once,
+        };
+    }
+
+    if matches.is_present("max-subscribers") {
+        config.max_subscriber_count = value_t_or_exit!(matches, "max-subscribers", usize);
+    }
+
+    if let Some(log_file) = matches.value_of("log-file") {
+        config.logging.path = log_file.to_owned();
+    }
+
+    if matches.is_present("log-append") {
+        config.logging.mode = match matches.value_of("log-append") {
+            Some("false") => WriteMode::Truncate,
+            _ => WriteMode::Append,
+        };
+    }
+
+    if matches.is_present("log-level") {
+        config.logging.level = value_t_or_exit!(matches, "log-level", LogLevel);
+    }
+
+    if matches.is_present("log-format") {
+        config.logging.format = value_t_or_exit!(matches, "log-format", LogFormat);
+    }
+
+    if matches.is_present("log-queue-size") {
+        config.logging.queue_size = value_t_or_exit!(matches, "log-queue-size", usize);
+    }
+
+    if matches.is_present("log-overflow") {
+        config.logging.overflow_policy = value_t_or_exit!(matches, "log-overflow", OverflowPolicy);
+    }
+}
+
+fn exit_with_error(err: &dyn std::error::Error) -> ! {
+    eprintln!("error: {}", err);
+    process::exit(1)
 }
@@ -0,0 +1,57 @@
+// Author: Karim Elmougi
+
+use clap::{App, Arg};
+use ift611_project::validate;
+use std::fs::File;
+use std::io::Write;
+use std::process;
+
+fn main() {
+    let matches = App::new("HFT Data Validator")
+        .version("0.1.0")
+        .author("Karim Elmougi <karim@elmougi.dev>")
+        .about("Checks that a CSV file can be replayed by the server, printing the invalid lines")
+        .arg(
+            Arg::with_name("INPUT")
+                .help("Sets the CSV file to check")
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("output")
+                .help("Writes the valid records to a cleaned file")
+                .short("o")
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE"),
+        )
+        .get_matches();
+
+    let path = matches.value_of("INPUT").unwrap();
+    let input = File::open(path).unwrap_or_else(|err| {
+        eprintln!("could not open {}: {}", path, err);
+        process::exit(2);
+    });
+    let mut cleaned = matches.value_of("output").map(|path| {
+        File::create(path).unwrap_or_else(|err| {
+            eprintln!("could not create {}: {}", path, err);
+            process::exit(2);
+        })
+    });
+
+    let report = validate::validate(input, cleaned.as_mut().map(|file| file as &mut dyn Write))
+        .unwrap_or_else(|err| {
+            eprintln!("could not validate {}: {}", path, err);
+            process::exit(2);
+        });
+    for issue in &report.issues {
+        println!("{}", issue);
+    }
+    eprintln!(
+        "{} of {} records are valid",
+        report.valid_count, report.record_count
+    );
+    if !report.is_valid() {
+        process::exit(1);
+    }
+}
@@ -1,26 +1,97 @@
 // Author: Karim Elmougi
 
-use crate::logger::Context;
+use crate::logger::{Context, Fields, BINARY_PAYLOAD_SIZE};
+use crate::metrics::{Histogram, Snapshot};
+use chrono::{DateTime, TimeZone, Utc};
 use circular_queue::CircularQueue;
 use reqwest::{Client, Method, Request, Url};
-use serde::{Deserialize, Serialize};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
 use std::convert::From;
 use std::default::Default;
 use std::error::Error;
 use std::fmt::{self, Display};
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Write};
 use std::net::TcpStream;
+use std::str::FromStr;
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug)]
 pub enum DecisionLogs {
     Buy,
     Sell,
     Wait,
+    Latency(LatencyStage, Percentiles),
 }
 
 impl Context for DecisionLogs {
-    fn context_string(&self) -> String {
-        format!("{:?}", self)
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        match self {
+            DecisionLogs::Latency(stage, percentiles) => {
+                write!(out, "{} latency: {}", stage, percentiles)
+            }
+            decision => write!(out, "{:?}", decision),
+        }
+    }
+
+    fn write_fields(&self, fields: &mut Fields) -> fmt::Result {
+        match self {
+            DecisionLogs::Latency(stage, percentiles) => {
+                fields.add("stage", stage)?;
+                fields.add("p50_ns", &percentiles.p50)?;
+                fields.add("p99_ns", &percentiles.p99)?;
+                fields.add("p999_ns", &percentiles.p999)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn event_id(&self) -> u16 {
+        match self {
+            DecisionLogs::Buy => 1,
+            DecisionLogs::Sell => 2,
+            DecisionLogs::Wait => 3,
+            DecisionLogs::Latency(..) => 4,
+        }
+    }
+
+    /// The stage's index followed by its percentiles in nanoseconds, capped
+    /// to 32 bits.
+    fn write_payload(&self, payload: &mut [u8; BINARY_PAYLOAD_SIZE]) {
+        if let DecisionLogs::Latency(stage, percentiles) = self {
+            payload[0] = *stage as u8;
+            let values = [percentiles.p50, percentiles.p99, percentiles.p999];
+            for (i, value) in values.iter().enumerate() {
+                let value = (*value).min(u64::from(u32::MAX)) as u32;
+                payload[4 + 4 * i..8 + 4 * i].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
+}
+
+impl DecisionLogs {
+    /// The decision or latency logged with `event_id` and `payload` in a binary
+    /// log, `None` for an unknown event id.
+    pub fn from_binary(event_id: u16, payload: &[u8; BINARY_PAYLOAD_SIZE]) -> Option<Self> {
+        let value = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&payload[4 + 4 * i..8 + 4 * i]);
+            u64::from(u32::from_le_bytes(bytes))
+        };
+        match event_id {
+            1 => Some(DecisionLogs::Buy),
+            2 => Some(DecisionLogs::Sell),
+            3 => Some(DecisionLogs::Wait),
+            4 => Some(DecisionLogs::Latency(
+                *LatencyStage::ALL.get(payload[0] as usize)?,
+                Percentiles {
+                    p50: value(0),
+                    p99: value(1),
+                    p999: value(2),
+                },
+            )),
+            _ => None,
+        }
     }
 }
 
@@ -61,6 +132,8 @@ impl TradingStrategy {
 
 #[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct Record {
+    /// Unix timestamp in milliseconds.
+    pub timestamp: i64,
     pub open: f32,
     pub high: f32,
     pub low: f32,
@@ -71,6 +144,7 @@ pub struct Record {
 impl Default for Record {
     fn default() -> Record {
         Record {
+            timestamp: 0,
             open: 32.0,
             high: 32.0,
             low: 32.0,
@@ -80,6 +154,17 @@ impl Default for Record {
     }
 }
 
+/// A pushed record, with the time it went through each stage.
+#[derive(Copy, Clone, Debug)]
+pub struct TimedRecord {
+    pub pair: TradingPair,
+    pub record: Record,
+    /// When the server wrote the record, if it sent that time along.
+    pub sent_at: Option<DateTime<Utc>>,
+    pub received_at: DateTime<Utc>,
+    pub parsed_at: DateTime<Utc>,
+}
+
 impl Record {
     pub fn to_csv_str(&self) -> String {
         format!(
@@ -91,6 +176,7 @@ impl Record {
     pub fn from_csv_str(csv_str: &str) -> Result<Self, Box<Error>> {
         let fields: Vec<_> = csv_str.split(',').map(str::trim).collect();
         Ok(Record {
+            timestamp: fields[0].parse()?,
             open: fields[3].parse()?,
             high: fields[4].parse()?,
             low: fields[5].parse()?,
@@ -100,9 +186,149 @@ impl Record {
     }
 }
 
-#[derive(Debug)]
+/// Reads the send time the server appends to pushed records, in nanoseconds
+/// since the Unix epoch. `csv_str` is the record, without the symbol tagging it.
+pub fn send_timestamp(csv_str: &str) -> Option<DateTime<Utc>> {
+    let nanos: i64 = csv_str.trim_end().split(',').nth(8)?.parse().ok()?;
+    Some(Utc.timestamp(
+        nanos.div_euclid(1_000_000_000),
+        nanos.rem_euclid(1_000_000_000) as u32,
+    ))
+}
+
+/// Stages between the server pushing a record and the client deciding on it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LatencyStage {
+    /// From the server writing the record to the client reading it.
+    Network,
+    Parsing,
+    Decision,
+    /// The whole way, from the server writing the record to the decision.
+    TickToTrade,
+}
+
+impl LatencyStage {
+    pub const ALL: [LatencyStage; 4] = [
+        LatencyStage::Network,
+        LatencyStage::Parsing,
+        LatencyStage::Decision,
+        LatencyStage::TickToTrade,
+    ];
+}
+
+impl Display for LatencyStage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            LatencyStage::Network => "network",
+            LatencyStage::Parsing => "parsing",
+            LatencyStage::Decision => "decision",
+            LatencyStage::TickToTrade => "tick-to-trade",
+        })
+    }
+}
+
+/// In nanoseconds.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Percentiles {
+    pub p50: u64,
+    pub p99: u64,
+    pub p999: u64,
+}
+
+impl Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "p50 {}ns, p99 {}ns, p99.9 {}ns",
+            self.p50, self.p99, self.p999
+        )
+    }
+}
+
+/// Latencies of every stage, for each record decided on. Several clients may
+/// record to the same stats concurrently.
+#[derive(Clone, Default)]
+pub struct LatencyStats {
+    histograms: [Histogram; 4],
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records the stages of `timed`. The network and tick-to-trade stages
+    /// are skipped when the server did not send its time.
+    pub fn record(&self, timed: &TimedRecord, decided_at: DateTime<Utc>) {
+        if let Some(sent_at) = timed.sent_at {
+            self.add(LatencyStage::Network, sent_at, timed.received_at);
+            self.add(LatencyStage::TickToTrade, sent_at, decided_at);
+        }
+        self.add(LatencyStage::Parsing, timed.received_at, timed.parsed_at);
+        self.add(LatencyStage::Decision, timed.parsed_at, decided_at);
+    }
+
+    fn add(&self, stage: LatencyStage, from: DateTime<Utc>, to: DateTime<Utc>) {
+        // the clocks of the server and client hosts may disagree
+        let nanos = (to - from).num_nanoseconds().unwrap_or(0).max(0) as u64;
+        self.histograms[stage as usize].record(nanos);
+    }
+
+    /// Adds the latencies recorded by `other`, e.g. by another client.
+    pub fn merge(&self, other: &LatencyStats) {
+        for (histogram, other_histogram) in self.histograms.iter().zip(other.histograms.iter()) {
+            histogram.merge(other_histogram);
+        }
+    }
+
+    pub fn count(&self, stage: LatencyStage) -> usize {
+        self.histograms[stage as usize].count() as usize
+    }
+
+    /// `None` until a latency of the stage was recorded.
+    pub fn percentiles(&self, stage: LatencyStage) -> Option<Percentiles> {
+        let histogram = &self.histograms[stage as usize];
+        Some(Percentiles {
+            p50: histogram.percentile(50.0)?,
+            p99: histogram.percentile(99.0)?,
+            p999: histogram.percentile(99.9)?,
+        })
+    }
+
+    pub fn snapshot(&self, stage: LatencyStage) -> Snapshot {
+        self.histograms[stage as usize].snapshot()
+    }
+}
+
+/// A JSON object of every stage's snapshot, keyed by the stage's name.
+impl Serialize for LatencyStats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(LatencyStage::ALL.len()))?;
+        for &stage in LatencyStage::ALL.iter() {
+            map.serialize_entry(&stage.to_string(), &self.snapshot(stage))?;
+        }
+        map.end()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum TradingPair {
     BTCUSD,
+    ETHUSD,
+    LTCUSD,
+}
+
+impl FromStr for TradingPair {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "BTCUSD" => Ok(TradingPair::BTCUSD),
+            "ETHUSD" => Ok(TradingPair::ETHUSD),
+            "LTCUSD" => Ok(TradingPair::LTCUSD),
+            _ => Err(format!("invalid trading pair: {}", s)),
+        }
+    }
 }
 
 impl TradingPair {
@@ -117,7 +343,21 @@ impl TradingPair {
     }
 
     pub fn subscribe(&self, url: &str) -> Result<Connection<BufReader<TcpStream>>, Box<Error>> {
-        let relative_path = format!("/subscribe/{}", self);
+        Self::subscribe_many(url, &[*self])
+    }
+
+    /// Subscribes to several pairs on a single connection, which yields their
+    /// records as they are pushed.
+    pub fn subscribe_many(
+        url: &str,
+        pairs: &[TradingPair],
+    ) -> Result<Connection<BufReader<TcpStream>>, Box<dyn Error>> {
+        let symbols = pairs
+            .iter()
+            .map(TradingPair::to_string)
+            .collect::<Vec<_>>()
+            .join(",");
+        let relative_path = format!("/subscribe/{}", symbols);
         let url = Url::parse(url)?.join(&relative_path)?;
 
         let request = Request::new(Method::POST, url);
@@ -126,7 +366,8 @@ impl TradingPair {
         let ip = response.remote_addr().ok_or("no remote ip")?.ip();
         let port = response.text()?.trim().parse::<u16>()?;
 
-        let stream = TcpStream::connect((ip, port))?;
+        let mut stream = TcpStream::connect((ip, port))?;
+        writeln!(stream, "{}", symbols)?;
         let reader = BufReader::new(stream);
         Ok(Connection::new(reader))
     }
@@ -153,12 +394,64 @@ impl<T> Connection<T> {
     }
 }
 
+impl<T: BufRead> Connection<T> {
+    /// Same as `next`, with the time the record went through each stage.
+    pub fn next_timed(&mut self) -> Option<TimedRecord> {
+        self.buffer.clear();
+        if self.reader.read_line(&mut self.buffer).ok()? == 0 {
+            return None;
+        }
+        let received_at = Utc::now();
+        // each line is tagged with the symbol of its pair
+        let separator = self.buffer.find(',')?;
+        let pair = self.buffer[..separator].parse().ok()?;
+        let line = &self.buffer[separator + 1..];
+        let record = Record::from_csv_str(line).ok()?;
+        Some(TimedRecord {
+            pair,
+            record,
+            sent_at: send_timestamp(line),
+            received_at,
+            parsed_at: Utc::now(),
+        })
+    }
+}
+
 impl<T: BufRead> Iterator for Connection<T> {
     type Item = Record;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.buffer.clear();
-        let _ = self.reader.read_line(&mut self.buffer).ok()?;
-        Record::from_csv_str(&self.buffer).ok()
+        self.next_timed().map(|timed| timed.record)
+    }
+}
+
+/// The latest records of each pair, to demultiplex a connection subscribed to
+/// several pairs.
+pub struct PairQueues {
+    queues: HashMap<TradingPair, CircularQueue<Record>>,
+    capacity: usize,
+}
+
+impl PairQueues {
+    pub fn with_capacity(capacity: usize) -> Self {
+        PairQueues {
+            queues: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Adds the record to the queue of its pair, which is returned.
+    pub fn push(&mut self, pair: TradingPair, record: Record) -> &CircularQueue<Record> {
+        let capacity = self.capacity;
+        let queue = self
+            .queues
+            .entry(pair)
+            .or_insert_with(|| CircularQueue::with_capacity(capacity));
+        queue.push(record);
+        queue
+    }
+
+    pub fn get(&self, pair: TradingPair) -> Option<&CircularQueue<Record>> {
+        self.queues.get(&pair)
     }
 }
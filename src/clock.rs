@@ -0,0 +1,133 @@
+// Author: Karim Elmougi
+
+use chrono::{DateTime, Utc};
+use crossbeam::channel::{self, Receiver, Sender, TrySendError};
+use std::convert::TryFrom;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Source of time of the logger and the server, so that tests can control it.
+pub trait Clock: Send + Sync {
+    /// Wall-clock time, e.g. to timestamp log messages.
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Monotonic time, only comparable to other instants of the same clock.
+    fn instant(&self) -> Instant;
+
+    /// Delivers the instant of each tick, every `period`, like
+    /// `crossbeam::channel::tick`.
+    fn ticker(&self, period: Duration) -> Receiver<Instant>;
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RealClock;
+
+impl Clock for RealClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn ticker(&self, period: Duration) -> Receiver<Instant> {
+        channel::tick(period)
+    }
+}
+
+/// Clock standing still until `advance` is called.
+pub struct SimulatedClock {
+    start_time: DateTime<Utc>,
+    start_instant: Instant,
+    state: Mutex<SimulatedState>,
+}
+
+struct SimulatedState {
+    elapsed: Duration,
+    tickers: Vec<SimulatedTicker>,
+}
+
+struct SimulatedTicker {
+    period: Duration,
+    // time since the clock's start
+    next_tick: Duration,
+    sender: Sender<Instant>,
+    is_connected: bool,
+}
+
+impl SimulatedClock {
+    pub fn new(start_time: DateTime<Utc>) -> Self {
+        SimulatedClock {
+            start_time,
+            start_instant: Instant::now(),
+            state: Mutex::new(SimulatedState {
+                elapsed: Duration::from_secs(0),
+                tickers: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.state.lock().unwrap().elapsed
+    }
+
+    /// Moves time forward, firing the tickers whose next tick is due. As with
+    /// real tickers, the ticks missed by a busy receiver are dropped.
+    pub fn advance(&self, duration: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.elapsed = state.elapsed.saturating_add(duration);
+        let elapsed = state.elapsed;
+
+        for ticker in state.tickers.iter_mut() {
+            if ticker.next_tick > elapsed {
+                continue;
+            }
+            let late_ticks = (elapsed - ticker.next_tick).as_nanos() / ticker.period.as_nanos();
+            // instants don't go as far as durations
+            let tick = self.start_instant.checked_add(ticker.next_tick);
+            // in nanoseconds, as a busy receiver can be late by more than
+            // `u32::MAX` periods
+            ticker.next_tick = (late_ticks + 1)
+                .checked_mul(ticker.period.as_nanos())
+                .and_then(|delay| delay.checked_add(ticker.next_tick.as_nanos()))
+                .and_then(duration_from_nanos)
+                .unwrap_or(Duration::MAX);
+            if let Some(tick) = tick {
+                if let Err(TrySendError::Disconnected(_)) = ticker.sender.try_send(tick) {
+                    ticker.is_connected = false;
+                }
+            }
+        }
+        state.tickers.retain(|ticker| ticker.is_connected);
+    }
+}
+
+fn duration_from_nanos(nanos: u128) -> Option<Duration> {
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.start_time + chrono::Duration::from_std(self.elapsed()).unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + self.elapsed()
+    }
+
+    fn ticker(&self, period: Duration) -> Receiver<Instant> {
+        let (sender, receiver) = channel::bounded(1);
+        let period = period.max(Duration::from_nanos(1));
+        let mut state = self.state.lock().unwrap();
+        let next_tick = state.elapsed + period;
+        state.tickers.push(SimulatedTicker {
+            period,
+            next_tick,
+            sender,
+            is_connected: true,
+        });
+        receiver
+    }
+}
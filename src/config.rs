@@ -0,0 +1,294 @@
+// Author: Karim Elmougi
+
+use crate::logger::{
+    LogDestination, LogFormat, LogLevel, OverflowPolicy, RotatingFileBuilder, Sink, WriteMode,
+};
+use crate::server::{ReplayMode, ServerBuilder};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_address: IpAddr,
+    pub http_port: u16,
+    pub push_port: u16,
+    pub period: u64,
+    pub replay_mode: ReplayMode,
+    pub max_subscriber_count: usize,
+    pub instruments: Vec<InstrumentConfig>,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InstrumentConfig {
+    pub symbol: String,
+    pub data_file: String,
+    pub max_records_amount: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    pub path: String,
+    pub mode: WriteMode,
+    pub level: LogLevel,
+    pub format: LogFormat,
+    pub queue_size: usize,
+    pub overflow_policy: OverflowPolicy,
+    pub batch_size: usize,
+    pub batch_max_latency_ms: u64,
+    pub rotation: Option<RotationConfig>,
+    pub sinks: Vec<SinkConfig>,
+}
+
+/// Rotation of the log file at `logging.path`, by size and/or age.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RotationConfig {
+    pub max_size: Option<u64>,
+    pub max_age_secs: Option<u64>,
+    pub kept_files: usize,
+    pub compress: bool,
+}
+
+/// An additional log destination; a `path` of "stdout" writes to the standard output.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SinkConfig {
+    pub path: String,
+    pub mode: WriteMode,
+    pub level: LogLevel,
+    pub format: LogFormat,
+    pub queue_size: usize,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            http_port: 80,
+            push_port: 0,
+            period: 1000,
+            replay_mode: ReplayMode::Once,
+            max_subscriber_count: 10,
+            instruments: vec![InstrumentConfig {
+                symbol: "BTCUSD".to_owned(),
+                data_file: "data.csv".to_owned(),
+                max_records_amount: None,
+            }],
+            logging: LoggingConfig::default(),
+        }
+    }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            path: "server_log.csv".to_owned(),
+            mode: WriteMode::Truncate,
+            level: LogLevel::Info,
+            format: LogFormat::Plain,
+            queue_size: 100,
+            overflow_policy: OverflowPolicy::Block,
+            batch_size: 64,
+            batch_max_latency_ms: 0,
+            rotation: None,
+            sinks: Vec::new(),
+        }
+    }
+}
+
+impl Default for RotationConfig {
+    fn default() -> Self {
+        RotationConfig {
+            max_size: None,
+            max_age_secs: None,
+            kept_files: 5,
+            compress: false,
+        }
+    }
+}
+
+impl Default for SinkConfig {
+    fn default() -> Self {
+        SinkConfig {
+            path: "stdout".to_owned(),
+            mode: WriteMode::Truncate,
+            level: LogLevel::Trace,
+            format: LogFormat::Plain,
+            queue_size: 100,
+        }
+    }
+}
+
+impl SinkConfig {
+    pub fn open(&self) -> io::Result<Sink> {
+        let destination = match self.path.as_str() {
+            "stdout" => LogDestination::Stdout,
+            path => LogDestination::File(path.to_owned(), self.mode),
+        };
+        Ok(Sink::new(destination.open()?)
+            .with_min_level(self.level)
+            .with_format(self.format)
+            .with_queue_size(self.queue_size))
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(String, io::Error),
+    Parse(String, toml::de::Error),
+    Invalid(&'static str, String),
+    Sink(String, io::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "could not read config file {}: {}", path, err),
+            ConfigError::Parse(path, err) => write!(f, "invalid config file {}: {}", path, err),
+            ConfigError::Invalid(field, reason) => {
+                write!(f, "invalid value for `{}`: {}", field, reason)
+            }
+            ConfigError::Sink(path, err) => write!(f, "could not open log sink {}: {}", path, err),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl ServerConfig {
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let content =
+            fs::read_to_string(path).map_err(|err| ConfigError::Io(path.to_owned(), err))?;
+        toml::from_str(&content).map_err(|err| ConfigError::Parse(path.to_owned(), err))
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |field, reason: &str| Err(ConfigError::Invalid(field, reason.to_owned()));
+
+        if self.period == 0 {
+            return invalid("period", "must be greater than 0");
+        }
+        if self.max_subscriber_count == 0 {
+            return invalid("max_subscriber_count", "must be greater than 0");
+        }
+        // each subscriber gets a share of the period to be written to
+        if self.period < self.max_subscriber_count as u64 {
+            return invalid("period", "must be at least `max_subscriber_count` μs");
+        }
+        if self.logging.queue_size == 0 {
+            return invalid("logging.queue_size", "must be greater than 0");
+        }
+        if self.logging.batch_size == 0 {
+            return invalid("logging.batch_size", "must be greater than 0");
+        }
+        if self.logging.path.is_empty() {
+            return invalid("logging.path", "must not be empty");
+        }
+        if let Some(rotation) = &self.logging.rotation {
+            if rotation.max_size.is_none() && rotation.max_age_secs.is_none() {
+                return invalid("logging.rotation", "requires `max_size` or `max_age_secs`");
+            }
+            if rotation.max_size == Some(0) || rotation.max_age_secs == Some(0) {
+                return invalid("logging.rotation", "limits must be greater than 0");
+            }
+        }
+        for sink in &self.logging.sinks {
+            if sink.path.is_empty() {
+                return invalid("logging.sinks.path", "must not be empty");
+            }
+            if sink.queue_size == 0 {
+                return invalid("logging.sinks.queue_size", "must be greater than 0");
+            }
+        }
+
+        if self.instruments.is_empty() {
+            return invalid("instruments", "at least one instrument is required");
+        }
+
+        for (i, instrument) in self.instruments.iter().enumerate() {
+            if self.instruments[..i]
+                .iter()
+                .any(|other| other.symbol == instrument.symbol)
+            {
+                return invalid("instruments.symbol", "must be unique");
+            }
+            if instrument.symbol.is_empty()
+                || !instrument.symbol.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return invalid(
+                    "instruments.symbol",
+                    "must be a non-empty alphanumeric string",
+                );
+            }
+            if instrument.data_file.is_empty() {
+                return invalid("instruments.data_file", "must not be empty");
+            }
+            if instrument.max_records_amount == Some(0) {
+                return invalid("instruments.max_records_amount", "must be greater than 0");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_toml_string(&self) -> String {
+        toml::to_string_pretty(self).expect("server config is always serializable")
+    }
+
+    /// Expects a config that passed `validate`. Fails if a log sink cannot be opened.
+    pub fn to_builder(&self) -> Result<ServerBuilder, ConfigError> {
+        let instrument = &self.instruments[0];
+
+        let mut builder = ServerBuilder::new(&instrument.data_file)
+            .with_symbol(&instrument.symbol)
+            .with_max_records_amount(instrument.max_records_amount)
+            .with_bind_address(self.bind_address)
+            .with_http_port(self.http_port)
+            .with_push_port(self.push_port)
+            .with_period(self.period)
+            .with_replay_mode(self.replay_mode)
+            .with_max_subscriber_count(self.max_subscriber_count)
+            .with_log_file(&self.logging.path, self.logging.mode)
+            .with_log_level(self.logging.level)
+            .with_log_format(self.logging.format)
+            .with_logger_queue_size(self.logging.queue_size)
+            .with_log_overflow_policy(self.logging.overflow_policy)
+            .with_log_batching(
+                self.logging.batch_size,
+                Duration::from_millis(self.logging.batch_max_latency_ms),
+            );
+        if let Some(rotation) = &self.logging.rotation {
+            builder = builder.with_log_rotating_file(
+                RotatingFileBuilder::new(&self.logging.path)
+                    .with_mode(self.logging.mode)
+                    .with_max_size(rotation.max_size)
+                    .with_max_age(rotation.max_age_secs.map(Duration::from_secs))
+                    .with_kept_files(rotation.kept_files)
+                    .with_compression(rotation.compress),
+            );
+        }
+        for instrument in &self.instruments[1..] {
+            builder = builder.with_instrument(
+                &instrument.symbol,
+                &instrument.data_file,
+                instrument.max_records_amount,
+            );
+        }
+        for sink in &self.logging.sinks {
+            let sink = sink
+                .open()
+                .map_err(|err| ConfigError::Sink(sink.path.clone(), err))?;
+            builder = builder.with_log_sink(sink);
+        }
+        Ok(builder)
+    }
+}
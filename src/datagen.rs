@@ -0,0 +1,241 @@
+// Author: Karim Elmougi
+
+use crate::client::Record;
+use chrono::{DateTime, TimeZone, Utc};
+use std::f64::consts::PI;
+use std::io::{self, Write};
+use std::time::Duration;
+
+const SECONDS_PER_YEAR: f64 = 365.0 * 24.0 * 3600.0;
+// steps simulated within each record, for its high and low
+const STEPS_PER_RECORD: usize = 8;
+// of the logarithm of the volumes
+const VOLUME_VOLATILITY: f64 = 0.5;
+
+/// How prices move from one step to the next. Rates, volatilities and
+/// intensities are annualized.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PriceModel {
+    GeometricBrownian,
+    /// Ornstein-Uhlenbeck process on the logarithm of the price, pulled back
+    /// towards `mean` at the given `speed`.
+    MeanReverting {
+        speed: f64,
+        mean: f64,
+    },
+    /// Geometric Brownian motion with log-normal jumps, Merton style.
+    JumpDiffusion {
+        intensity: f64,
+        jump_mean: f64,
+        jump_volatility: f64,
+    },
+}
+
+/// Reproducible synthetic records, in the CSV format the server loads. The
+/// same seed and settings always give the same records.
+pub struct MarketDataGenerator {
+    symbol: String,
+    model: PriceModel,
+    drift: f64,
+    volatility: f64,
+    mean_volume: f64,
+    interval: Duration,
+    // `None` once past the dates chrono supports
+    time: Option<DateTime<Utc>>,
+    price: f64,
+    rng: SplitMix64,
+}
+
+impl MarketDataGenerator {
+    pub fn new(seed: u64) -> Self {
+        MarketDataGenerator {
+            symbol: "BTCUSD".to_owned(),
+            model: PriceModel::GeometricBrownian,
+            drift: 0.0,
+            volatility: 0.8,
+            mean_volume: 50.0,
+            interval: Duration::from_secs(60),
+            time: Some(Utc.ymd(2019, 1, 1).and_hms(0, 0, 0)),
+            price: 3600.0,
+            rng: SplitMix64(seed),
+        }
+    }
+
+    pub fn with_symbol(self, symbol: &str) -> Self {
+        Self {
+            symbol: symbol.to_owned(),
+            ..self
+        }
+    }
+
+    pub fn with_model(self, model: PriceModel) -> Self {
+        Self { model, ..self }
+    }
+
+    pub fn with_start_price(self, price: f64) -> Self {
+        Self { price, ..self }
+    }
+
+    pub fn with_drift(self, drift: f64) -> Self {
+        Self { drift, ..self }
+    }
+
+    pub fn with_volatility(self, volatility: f64) -> Self {
+        Self { volatility, ..self }
+    }
+
+    pub fn with_mean_volume(self, mean_volume: f64) -> Self {
+        Self {
+            mean_volume,
+            ..self
+        }
+    }
+
+    /// Time between two records.
+    pub fn with_interval(self, interval: Duration) -> Self {
+        Self { interval, ..self }
+    }
+
+    pub fn with_start_time(self, time: DateTime<Utc>) -> Self {
+        Self {
+            time: Some(time),
+            ..self
+        }
+    }
+
+    /// Writes the header and `count` records. Prices are written in full, as
+    /// small prices would otherwise be rounded to 0.
+    pub fn write_csv<W: Write>(&mut self, writer: W, count: usize) -> io::Result<()> {
+        let mut writer = io::BufWriter::new(writer);
+        writeln!(
+            writer,
+            "Unix Timestamp,Date,Symbol,Open,High,Low,Close,Volume"
+        )?;
+        for _ in 0..count {
+            let record = self.next_record().ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "the timestamps of the records are out of range",
+                )
+            })?;
+            let date = Utc.timestamp_millis(record.timestamp);
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{:.4}",
+                record.timestamp,
+                date.format("%Y-%m-%d %H:%M:%S"),
+                self.symbol,
+                record.open,
+                record.high,
+                record.low,
+                record.close,
+                record.volume
+            )?;
+        }
+        writer.flush()
+    }
+
+    /// `None` once the timestamps go past the dates chrono supports, after a
+    /// huge interval or many records.
+    pub fn next_record(&mut self) -> Option<Record> {
+        let time = self.time?;
+        let step = self.interval.as_secs_f64() / SECONDS_PER_YEAR / STEPS_PER_RECORD as f64;
+        let open = self.price;
+        let (mut high, mut low) = (open, open);
+        for _ in 0..STEPS_PER_RECORD {
+            self.price = self.next_price(step);
+            high = high.max(self.price);
+            low = low.min(self.price);
+        }
+
+        let volume = self.mean_volume
+            * (VOLUME_VOLATILITY * self.rng.next_normal()
+                - VOLUME_VOLATILITY * VOLUME_VOLATILITY / 2.0)
+                .exp();
+        let record = Record {
+            timestamp: time.timestamp_millis(),
+            open: open as f32,
+            high: high as f32,
+            low: low as f32,
+            close: self.price as f32,
+            volume,
+        };
+        self.time = chrono::Duration::from_std(self.interval)
+            .ok()
+            .and_then(|interval| time.checked_add_signed(interval));
+        Some(record)
+    }
+
+    /// The price after `dt` years.
+    fn next_price(&mut self, dt: f64) -> f64 {
+        let diffusion = self.volatility * dt.sqrt() * self.rng.next_normal();
+        let variance_drift = self.volatility * self.volatility / 2.0;
+        match self.model {
+            PriceModel::GeometricBrownian => {
+                self.price * ((self.drift - variance_drift) * dt + diffusion).exp()
+            }
+            PriceModel::MeanReverting { speed, mean } => {
+                // exact discretization of the process
+                let decay = (-speed * dt).exp();
+                let deviation = if speed > 0.0 {
+                    self.volatility * ((1.0 - decay * decay) / (2.0 * speed)).sqrt()
+                } else {
+                    self.volatility * dt.sqrt()
+                };
+                let log_mean = mean.ln();
+                let log_price = log_mean
+                    + (self.price.ln() - log_mean) * decay
+                    + deviation * self.rng.next_normal();
+                log_price.exp()
+            }
+            PriceModel::JumpDiffusion {
+                intensity,
+                jump_mean,
+                jump_volatility,
+            } => {
+                // the drift is compensated for the jumps' expected size
+                let expected_jump =
+                    (jump_mean + jump_volatility * jump_volatility / 2.0).exp() - 1.0;
+                let mut log_return =
+                    (self.drift - variance_drift - intensity * expected_jump) * dt + diffusion;
+                if self.rng.next_f64() < intensity * dt {
+                    log_return += jump_mean + jump_volatility * self.rng.next_normal();
+                }
+                self.price * log_return.exp()
+            }
+        }
+    }
+}
+
+impl Iterator for MarketDataGenerator {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record()
+    }
+}
+
+/// SplitMix64, simple and good enough to not depend on the output of another
+/// crate's generator staying the same.
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in (0, 1].
+    fn next_f64(&mut self) -> f64 {
+        ((self.next_u64() >> 11) + 1) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, with the Box-Muller transform.
+    fn next_normal(&mut self) -> f64 {
+        let radius = (-2.0 * self.next_f64().ln()).sqrt();
+        radius * (2.0 * PI * self.next_f64()).cos()
+    }
+}
@@ -1,3 +1,9 @@
+pub mod bars;
 pub mod client;
+pub mod clock;
+pub mod config;
+pub mod datagen;
 pub mod logger;
+pub mod metrics;
 pub mod server;
+pub mod validate;
@@ -1,35 +1,488 @@
 // Author: Karim Elmougi
 
-use chrono::{DateTime, Utc};
-use crossbeam::channel::{self, Sender};
-use std::fmt::{self, Display, Formatter};
-use std::io::Write;
-use std::string::ToString;
-use std::thread;
+use crate::clock::{Clock, RealClock};
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc};
+use circular_queue::CircularQueue;
+use crossbeam::channel::{
+    self, select, Receiver, RecvError, RecvTimeoutError, Sender, TryRecvError, TrySendError,
+};
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::fmt::{self, Display, Formatter, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, stdout, Read, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LogLevel {
+    Trace,
+    Debug,
     Info,
     Warning,
     Error,
 }
 
+impl LogLevel {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LogLevel::Trace => "TRACE",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Info => "INFO",
+            LogLevel::Warning => "WARNING",
+            LogLevel::Error => "ERROR",
+        }
+    }
+
+    fn from_usize(level: usize) -> Self {
+        match level {
+            0 => LogLevel::Trace,
+            1 => LogLevel::Debug,
+            2 => LogLevel::Info,
+            3 => LogLevel::Warning,
+            _ => LogLevel::Error,
+        }
+    }
+}
+
 impl Display for LogLevel {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                LogLevel::Info => "INFO",
-                LogLevel::Warning => "WARNING",
-                LogLevel::Error => "ERROR",
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "trace" => Ok(LogLevel::Trace),
+            "debug" => Ok(LogLevel::Debug),
+            "info" => Ok(LogLevel::Info),
+            "warning" => Ok(LogLevel::Warning),
+            "error" => Ok(LogLevel::Error),
+            _ => Err(format!("invalid log level: {}", s)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteMode {
+    Append,
+    Truncate,
+}
+
+pub fn open_log_file(path: &str, mode: WriteMode) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    match mode {
+        WriteMode::Append => options.append(true),
+        WriteMode::Truncate => options.write(true).truncate(true),
+    };
+    options.create(true).open(path)
+}
+
+pub enum LogDestination {
+    File(String, WriteMode),
+    Stdout,
+    Writer(Box<dyn Write + Send>),
+    RotatingFile(RotatingFileBuilder),
+}
+
+impl LogDestination {
+    pub fn open(self) -> io::Result<Box<dyn Write + Send>> {
+        Ok(match self {
+            LogDestination::File(path, mode) => Box::new(open_log_file(&path, mode)?),
+            LogDestination::Stdout => Box::new(stdout()),
+            LogDestination::Writer(writer) => writer,
+            LogDestination::RotatingFile(builder) => Box::new(builder.open()?),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RotatingFileBuilder {
+    path: String,
+    mode: WriteMode,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    kept_files: usize,
+    compress: bool,
+    record_size: Option<usize>,
+}
+
+impl RotatingFileBuilder {
+    pub fn new(path: &str) -> Self {
+        RotatingFileBuilder {
+            path: path.to_owned(),
+            mode: WriteMode::Append,
+            max_size: None,
+            max_age: None,
+            kept_files: 5,
+            compress: false,
+            record_size: None,
+        }
+    }
+
+    pub fn with_mode(self, mode: WriteMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    /// Rotates before a line would make the file bigger than `max_size` bytes.
+    pub fn with_max_size(self, max_size: Option<u64>) -> Self {
+        Self { max_size, ..self }
+    }
+
+    /// Rotates on the first write once the file has been open for `max_age`.
+    pub fn with_max_age(self, max_age: Option<Duration>) -> Self {
+        Self { max_age, ..self }
+    }
+
+    /// Rotated files are named `path.1` (the most recent) to `path.N`.
+    pub fn with_kept_files(self, kept_files: usize) -> Self {
+        Self { kept_files, ..self }
+    }
+
+    /// Gzips rotated files to `path.N.gz`, on a separate thread.
+    pub fn with_compression(self, compress: bool) -> Self {
+        Self { compress, ..self }
+    }
+
+    /// Cuts files between records of `record_size` bytes rather than after
+    /// lines, e.g. `BINARY_RECORD_SIZE` for `LogFormat::Binary`, whose records
+    /// can contain newline bytes.
+    pub fn with_record_size(self, record_size: Option<usize>) -> Self {
+        Self {
+            record_size,
+            ..self
+        }
+    }
+
+    pub fn open(self) -> io::Result<RotatingFile> {
+        let file = open_log_file(&self.path, self.mode)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            config: self,
+            file,
+            size,
+            opened_at: Instant::now(),
+            compression: None,
+        })
+    }
+}
+
+/// Log file rolled over by size or age, keeping a limited amount of old files.
+pub struct RotatingFile {
+    config: RotatingFileBuilder,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    compression: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl RotatingFile {
+    fn is_too_old(&self) -> bool {
+        match self.config.max_age {
+            Some(max_age) => self.opened_at.elapsed() >= max_age,
+            None => false,
+        }
+    }
+
+    /// Length of the start of `buf` fitting in the file, cut after a line or
+    /// record so that batches are split between files.
+    fn fitting_len(&self, buf: &[u8]) -> usize {
+        match self.config.max_size {
+            Some(max_size) if self.size + buf.len() as u64 > max_size => {
+                let room = max_size.saturating_sub(self.size).min(buf.len() as u64);
+                match self.config.record_size {
+                    // files start with a record, as they are cut between records
+                    Some(record_size) => {
+                        let record_size = record_size as u64;
+                        let end = (self.size + room) / record_size * record_size;
+                        end.saturating_sub(self.size) as usize
+                    }
+                    None => buf[..room as usize]
+                        .iter()
+                        .rposition(|&byte| byte == b'\n')
+                        .map_or(0, |end| end + 1),
+                }
+            }
+            _ => buf.len(),
+        }
+    }
+
+    /// Length of the first line or record of `buf`.
+    fn first_len(&self, buf: &[u8]) -> usize {
+        match self.config.record_size {
+            Some(record_size) => {
+                let record_size = record_size as u64;
+                (record_size - self.size % record_size).min(buf.len() as u64) as usize
+            }
+            None => buf
+                .iter()
+                .position(|&byte| byte == b'\n')
+                .map_or(buf.len(), |end| end + 1),
+        }
+    }
+
+    fn rotated_path(&self, index: usize, compressed: bool) -> String {
+        let extension = if compressed { ".gz" } else { "" };
+        format!("{}.{}{}", self.config.path, index, extension)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        // the previous rotated file must be compressed before being renamed
+        self.wait_for_compression();
+
+        let kept_files = self.config.kept_files;
+        for &compressed in &[false, true] {
+            remove_if_exists(&self.rotated_path(kept_files.max(1), compressed))?;
+            for index in (1..kept_files).rev() {
+                rename_if_exists(
+                    &self.rotated_path(index, compressed),
+                    &self.rotated_path(index + 1, compressed),
+                )?;
+            }
+        }
+        if kept_files == 0 {
+            fs::remove_file(&self.config.path)?;
+        } else {
+            let rotated = self.rotated_path(1, false);
+            fs::rename(&self.config.path, &rotated)?;
+            if self.config.compress {
+                let compressed = self.rotated_path(1, true);
+                self.compression = Some(thread::spawn(move || gzip_file(&rotated, &compressed)));
             }
-        )
+        }
+
+        self.file = open_log_file(&self.config.path, WriteMode::Truncate)?;
+        self.size = 0;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn wait_for_compression(&mut self) {
+        if let Some(compression) = self.compression.take() {
+            if let Err(err) = compression.join().unwrap() {
+                eprintln!(
+                    "{}, could not compress rotated log file: {}",
+                    Utc::now(),
+                    err
+                );
+            }
+        }
     }
 }
 
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_too_old() {
+            self.rotate()?;
+        }
+        let mut len = self.fitting_len(buf);
+        if len == 0 && self.size > 0 {
+            self.rotate()?;
+            len = self.fitting_len(buf);
+        }
+        if len == 0 {
+            // a line or record bigger than the maximum size gets a file of its own
+            len = self.first_len(buf);
+        }
+        let written = self.file.write(&buf[..len])?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for RotatingFile {
+    fn drop(&mut self) {
+        self.wait_for_compression();
+    }
+}
+
+fn remove_if_exists(path: &str) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn rename_if_exists(from: &str, to: &str) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn gzip_file(from: &str, to: &str) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(File::create(to)?, Compression::default());
+    io::copy(&mut File::open(from)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(from)
+}
+
+/// In-memory writer whose clones share the same buffer, so that the content
+/// written by a logger can be read back, e.g. in tests.
+#[derive(Clone, Default)]
+pub struct MemoryWriter(Arc<Mutex<Vec<u8>>>);
+
+impl MemoryWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// In-memory writer keeping only the last lines written to it, whose clones
+/// share the same lines, e.g. to expose the recent logs of a running server.
+#[derive(Clone)]
+pub struct MemoryRing(Arc<Mutex<RingLines>>);
+
+struct RingLines {
+    lines: CircularQueue<String>,
+    partial_line: String,
+}
+
+impl MemoryRing {
+    pub fn with_capacity(capacity: usize) -> Self {
+        MemoryRing(Arc::new(Mutex::new(RingLines {
+            lines: CircularQueue::with_capacity(capacity),
+            partial_line: String::new(),
+        })))
+    }
+
+    /// The kept lines, from the oldest to the most recent, without their newline.
+    pub fn lines(&self) -> Vec<String> {
+        let ring = self.0.lock().unwrap();
+        let mut lines: Vec<String> = ring.lines.iter().cloned().collect();
+        lines.reverse();
+        lines
+    }
+}
+
+impl Write for MemoryRing {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut ring = self.0.lock().unwrap();
+        for c in String::from_utf8_lossy(buf).chars() {
+            if c == '\n' {
+                let line = mem::take(&mut ring.partial_line);
+                ring.lines.push(line);
+            } else {
+                ring.partial_line.push(c);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a log message is about. A context is only formatted by the logger's
+/// thread, straight into the line being built, so logging one never allocates.
 pub trait Context: Send {
-    fn context_string(&self) -> String;
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result;
+
+    /// Key/value pairs written as separate fields by structured formats.
+    fn write_fields(&self, _fields: &mut Fields) -> fmt::Result {
+        Ok(())
+    }
+
+    /// Identifies the kind of message in binary logs.
+    fn event_id(&self) -> u16 {
+        0
+    }
+
+    /// Fills the fixed-size payload of binary logs, zeroed beforehand.
+    fn write_payload(&self, _payload: &mut [u8; BINARY_PAYLOAD_SIZE]) {}
+}
+
+/// Receives the fields of a `Context`, see `Context::write_fields`.
+pub struct Fields<'a> {
+    line: &'a mut String,
+    is_empty: bool,
+}
+
+impl<'a> Fields<'a> {
+    pub fn add(&mut self, key: &str, value: &dyn Display) -> fmt::Result {
+        if !self.is_empty {
+            self.line.push(',');
+        }
+        self.is_empty = false;
+        push_json_string(self.line, key);
+        self.line.push_str(":\"");
+        write!(JsonEscaper(self.line), "{}", value)?;
+        self.line.push('"');
+        Ok(())
+    }
+}
+
+thread_local! {
+    // scratch space for the contexts that must be escaped as a whole, reused
+    // by every message formatted on the thread
+    static ARENA: RefCell<String> = RefCell::new(String::with_capacity(ARENA_CAPACITY));
+}
+
+const ARENA_CAPACITY: usize = 1024;
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// `timestamp, level, context` lines without any escaping, the default.
+    Plain,
+    /// `timestamp,level,context` CSV records, quoted when needed.
+    Csv,
+    /// One JSON object per line, including the context's fields.
+    JsonLines,
+    /// Fixed-size records holding the event id and payload of the context
+    /// instead of its text, see `BinaryRecord`.
+    Binary,
+}
+
+const LOG_FORMAT_COUNT: usize = 4;
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(LogFormat::Plain),
+            "csv" => Ok(LogFormat::Csv),
+            "json_lines" => Ok(LogFormat::JsonLines),
+            "binary" => Ok(LogFormat::Binary),
+            _ => Err(format!("invalid log format: {}", s)),
+        }
+    }
 }
 
 struct LogMessage<T: Context> {
@@ -46,58 +499,917 @@ impl<T: Context> LogMessage<T> {
             context,
         }
     }
+
+    /// Formats the message into `buf`, which must be empty.
+    fn format_into(&self, format: LogFormat, buf: &mut Vec<u8>) {
+        if format == LogFormat::Binary {
+            return self.encode_binary(buf);
+        }
+
+        // an empty buffer is valid text, so this neither copies nor fails
+        let mut line = String::from_utf8(mem::take(buf)).unwrap();
+        // writing into a `String` only fails if the context fails
+        let _ = match format {
+            LogFormat::Plain => self.format_plain(&mut line),
+            LogFormat::Csv => self.format_csv(&mut line),
+            _ => self.format_json(&mut line),
+        };
+        *buf = line.into_bytes();
+    }
+
+    fn encode_binary(&self, buf: &mut Vec<u8>) {
+        let mut payload = [0; BINARY_PAYLOAD_SIZE];
+        self.context.write_payload(&mut payload);
+        BinaryRecord {
+            timestamp: self.timestamp,
+            level: self.level,
+            event_id: self.context.event_id(),
+            payload,
+        }
+        .encode_into(buf);
+    }
+
+    fn format_plain(&self, line: &mut String) -> fmt::Result {
+        write!(line, "{}, {}, ", self.timestamp, self.level)?;
+        self.context.write_context(line)?;
+        line.push('\n');
+        Ok(())
+    }
+
+    fn format_csv(&self, line: &mut String) -> fmt::Result {
+        write_rfc3339(line, self.timestamp)?;
+        line.push(',');
+        line.push_str(self.level.as_str());
+        line.push(',');
+        ARENA.with(|arena| -> fmt::Result {
+            let mut arena = arena.borrow_mut();
+            arena.clear();
+            self.context.write_context(&mut *arena)?;
+            push_csv_field(line, &arena);
+            Ok(())
+        })?;
+        line.push('\n');
+        Ok(())
+    }
+
+    fn format_json(&self, line: &mut String) -> fmt::Result {
+        line.push_str("{\"timestamp\":\"");
+        write_rfc3339(line, self.timestamp)?;
+        line.push_str("\",\"level\":\"");
+        line.push_str(self.level.as_str());
+        line.push_str("\",\"context\":\"");
+        self.context.write_context(&mut JsonEscaper(line))?;
+        line.push_str("\",\"fields\":{");
+        self.context.write_fields(&mut Fields {
+            line,
+            is_empty: true,
+        })?;
+        line.push_str("}}\n");
+        Ok(())
+    }
 }
 
-impl<T: Context> Display for LogMessage<T> {
-    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        writeln!(
-            f,
-            "{}, {}, {}",
-            self.timestamp,
-            self.level,
-            self.context.context_string()
-        )
+/// Same as `to_rfc3339_opts(SecondsFormat::Nanos, true)`, without allocating.
+fn write_rfc3339(line: &mut String, timestamp: DateTime<Utc>) -> fmt::Result {
+    write!(
+        line,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        timestamp.year(),
+        timestamp.month(),
+        timestamp.day(),
+        timestamp.hour(),
+        timestamp.minute(),
+        timestamp.second(),
+        timestamp.nanosecond()
+    )
+}
+
+fn push_csv_field(line: &mut String, field: &str) {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        line.push('"');
+        for c in field.chars() {
+            if c == '"' {
+                line.push('"');
+            }
+            line.push(c);
+        }
+        line.push('"');
+    } else {
+        line.push_str(field);
+    }
+}
+
+fn push_json_string(line: &mut String, value: &str) {
+    line.push('"');
+    let _ = JsonEscaper(line).write_str(value);
+    line.push('"');
+}
+
+/// Escapes what is written through it to fit in a JSON string.
+struct JsonEscaper<'a>(&'a mut String);
+
+impl<'a> fmt::Write for JsonEscaper<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            match c {
+                '"' => self.0.push_str("\\\""),
+                '\\' => self.0.push_str("\\\\"),
+                '\n' => self.0.push_str("\\n"),
+                '\r' => self.0.push_str("\\r"),
+                '\t' => self.0.push_str("\\t"),
+                c if c < ' ' => write!(self.0, "\\u{:04x}", c as u32)?,
+                c => self.0.push(c),
+            }
+        }
+        Ok(())
+    }
+}
+
+pub const BINARY_PAYLOAD_SIZE: usize = 20;
+pub const BINARY_RECORD_SIZE: usize = 12 + BINARY_PAYLOAD_SIZE;
+
+/// A message of a binary log: the timestamp in nanoseconds since the Unix
+/// epoch, the level, an unused byte and the event id, all little-endian,
+/// followed by the payload.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct BinaryRecord {
+    pub timestamp: DateTime<Utc>,
+    pub level: LogLevel,
+    pub event_id: u16,
+    pub payload: [u8; BINARY_PAYLOAD_SIZE],
+}
+
+impl BinaryRecord {
+    pub fn encode_into(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.timestamp.timestamp_nanos().to_le_bytes());
+        buf.push(self.level as u8);
+        buf.push(0);
+        buf.extend_from_slice(&self.event_id.to_le_bytes());
+        buf.extend_from_slice(&self.payload);
+    }
+
+    pub fn decode(bytes: &[u8; BINARY_RECORD_SIZE]) -> Result<Self, String> {
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&bytes[..8]);
+        let timestamp = i64::from_le_bytes(timestamp);
+        let level = match bytes[8] as usize {
+            level if level <= LogLevel::Error as usize => LogLevel::from_usize(level),
+            level => return Err(format!("invalid log level: {}", level)),
+        };
+        let mut payload = [0; BINARY_PAYLOAD_SIZE];
+        payload.copy_from_slice(&bytes[12..]);
+
+        Ok(BinaryRecord {
+            timestamp: Utc.timestamp(
+                timestamp.div_euclid(1_000_000_000),
+                timestamp.rem_euclid(1_000_000_000) as u32,
+            ),
+            level,
+            event_id: u16::from_le_bytes([bytes[10], bytes[11]]),
+            payload,
+        })
+    }
+
+    /// Reads the next record, or `None` at the end of `reader`.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Option<Self>> {
+        let mut bytes = [0; BINARY_RECORD_SIZE];
+        let mut read = 0;
+        while read < BINARY_RECORD_SIZE {
+            match reader.read(&mut bytes[read..]) {
+                Ok(0) if read == 0 => return Ok(None),
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "truncated binary log record",
+                    ))
+                }
+                Ok(n) => read += n,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Self::decode(&bytes)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+}
+
+/// What a `Logger` does with a message when its queue is full.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Waits for the logger's thread to make room, the default.
+    Block,
+    DropNewest,
+    DropOldest,
+    /// Drops the newest message and has the logger's thread write how many
+    /// messages were dropped once it catches up.
+    DropAndReport,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(OverflowPolicy::Block),
+            "drop_newest" => Ok(OverflowPolicy::DropNewest),
+            "drop_oldest" => Ok(OverflowPolicy::DropOldest),
+            "drop_and_report" => Ok(OverflowPolicy::DropAndReport),
+            _ => Err(format!("invalid overflow policy: {}", s)),
+        }
+    }
+}
+
+enum Control {
+    Flush(Sender<()>),
+    Shutdown,
+}
+
+enum SinkMessage {
+    Batch(Vec<u8>),
+    Flush(Sender<()>),
+}
+
+/// A destination for log messages, with its own level filter and format.
+/// Each sink is written to by its own thread: when a logger has several
+/// sinks, a sink whose queue is full misses messages instead of holding up
+/// the others. A logger's only sink waits for room instead.
+pub struct Sink {
+    writer: Box<dyn Write + Send>,
+    min_level: LogLevel,
+    format: LogFormat,
+    queue_size: usize,
+}
+
+impl Sink {
+    pub fn new<U: 'static + Send + Write>(writer: U) -> Self {
+        Sink {
+            writer: Box::new(writer),
+            min_level: LogLevel::Trace,
+            format: LogFormat::Plain,
+            queue_size: 100,
+        }
+    }
+
+    pub fn with_min_level(self, min_level: LogLevel) -> Self {
+        Self { min_level, ..self }
+    }
+
+    pub fn with_format(self, format: LogFormat) -> Self {
+        Self { format, ..self }
+    }
+
+    pub fn with_queue_size(self, queue_size: usize) -> Self {
+        Self { queue_size, ..self }
+    }
+
+    fn start(self) -> SinkThread {
+        let (sender, receiver) = channel::bounded(self.queue_size);
+        // written batches go back to the logger's thread, to be filled again
+        let (spare_sender, spare_batches) = channel::bounded(self.queue_size + 1);
+        let mut writer = self.writer;
+
+        let thread = thread::spawn(move || {
+            for message in receiver {
+                match message {
+                    SinkMessage::Batch(mut batch) => {
+                        write_batch(&mut writer, &batch);
+                        batch.clear();
+                        let _ = spare_sender.try_send(batch);
+                    }
+                    SinkMessage::Flush(done) => {
+                        flush_writer(&mut writer);
+                        let _ = done.send(());
+                    }
+                }
+            }
+            flush_writer(&mut writer);
+        });
+
+        SinkThread {
+            sender,
+            min_level: self.min_level,
+            format: self.format,
+            batch: Vec::new(),
+            batch_lines: 0,
+            spare_batches,
+            dropped_lines: Arc::new(AtomicUsize::new(0)),
+            thread,
+        }
+    }
+}
+
+struct SinkThread {
+    sender: Sender<SinkMessage>,
+    min_level: LogLevel,
+    format: LogFormat,
+    // messages waiting to be sent to the sink, in a single write
+    batch: Vec<u8>,
+    batch_lines: usize,
+    spare_batches: Receiver<Vec<u8>>,
+    dropped_lines: Arc<AtomicUsize>,
+    thread: JoinHandle<()>,
+}
+
+/// Fans the messages out to the sinks, from the logger's thread.
+struct Dispatcher {
+    sinks: Vec<SinkThread>,
+    // reused for every message, one per format
+    formatted: [Vec<u8>; LOG_FORMAT_COUNT],
+}
+
+impl Dispatcher {
+    fn new(sinks: Vec<SinkThread>) -> Self {
+        Dispatcher {
+            sinks,
+            formatted: Default::default(),
+        }
+    }
+
+    /// Adds the message to the batch of the sinks accepting its level. It is
+    /// formatted once per format, whatever the amount of sinks using it.
+    fn add_message<T: Context>(&mut self, log_message: &LogMessage<T>) {
+        let formatted = &mut self.formatted;
+        let mut is_formatted = [false; LOG_FORMAT_COUNT];
+
+        for sink in self
+            .sinks
+            .iter_mut()
+            .filter(|sink| log_message.level >= sink.min_level)
+        {
+            let index = sink.format as usize;
+            if !is_formatted[index] {
+                formatted[index].clear();
+                log_message.format_into(sink.format, &mut formatted[index]);
+                is_formatted[index] = true;
+            }
+            sink.batch.extend_from_slice(&formatted[index]);
+            sink.batch_lines += 1;
+        }
+    }
+
+    fn send_batches(&mut self) {
+        let only_sink = self.sinks.len() == 1;
+
+        for sink in self.sinks.iter_mut().filter(|sink| sink.batch_lines > 0) {
+            let spare_batch = sink.spare_batches.try_recv().unwrap_or_default();
+            let batch = SinkMessage::Batch(mem::replace(&mut sink.batch, spare_batch));
+            if only_sink {
+                let _ = sink.sender.send(batch);
+            } else if let Err(err) = sink.sender.try_send(batch) {
+                sink.dropped_lines
+                    .fetch_add(sink.batch_lines, Ordering::Relaxed);
+                if let SinkMessage::Batch(mut batch) = err.into_inner() {
+                    batch.clear();
+                    sink.batch = batch;
+                }
+            }
+            sink.batch_lines = 0;
+        }
+    }
+
+    fn flush(&self) {
+        let (done_sender, done_receiver) = channel::bounded(self.sinks.len());
+        for sink in &self.sinks {
+            let _ = sink.sender.send(SinkMessage::Flush(done_sender.clone()));
+        }
+        drop(done_sender);
+        for _ in done_receiver {}
+    }
+
+    fn shutdown(self) {
+        for sink in self.sinks {
+            drop(sink.sender);
+            sink.thread.join().unwrap();
+        }
     }
 }
 
 #[derive(Clone)]
-pub struct Logger<T: Context>(Sender<LogMessage<T>>);
-
-impl<T: 'static + Context> Logger<T> {
-    pub fn start<U: 'static + Send + Write>(mut writer: U, message_queue_size: usize) -> Self {
-        let (send_chan, recv_chan) = channel::bounded::<LogMessage<T>>(message_queue_size);
-
-        thread::spawn(move || {
-            while let Ok(log_message) = recv_chan.recv() {
-                let result = writer.write_all(log_message.to_string().as_bytes());
-                if result.is_err() {
-                    eprintln!(
-                        "{}, logger could not write log message successfully: {}",
-                        Utc::now(),
-                        result.unwrap_err()
+pub struct Logger<T: Context> {
+    sender: Sender<LogMessage<T>>,
+    // only kept by `OverflowPolicy::DropOldest` loggers, to make room in the queue
+    receiver: Option<Receiver<LogMessage<T>>>,
+    overflow_policy: OverflowPolicy,
+    min_level: Arc<AtomicUsize>,
+    dropped_messages: Arc<AtomicUsize>,
+    clock: Arc<dyn Clock>,
+}
+
+/// Controls the thread of a started `Logger`. Everything logged before a call
+/// to `flush` or `shutdown` is written when it returns.
+pub struct LoggerHandle {
+    control: Sender<Control>,
+    thread: JoinHandle<()>,
+    dropped_lines: Vec<Arc<AtomicUsize>>,
+    external: Logger<ExternalRecord>,
+}
+
+pub struct LoggerBuilder {
+    sinks: Vec<Sink>,
+    message_queue_size: usize,
+    overflow_policy: OverflowPolicy,
+    min_level: LogLevel,
+    batch_size: usize,
+    batch_max_latency: Duration,
+    clock: Arc<dyn Clock>,
+}
+
+impl LoggerBuilder {
+    pub fn new<U: 'static + Send + Write>(writer: U) -> Self {
+        LoggerBuilder {
+            sinks: vec![Sink::new(writer)],
+            message_queue_size: 100,
+            overflow_policy: OverflowPolicy::Block,
+            min_level: LogLevel::Info,
+            batch_size: 64,
+            batch_max_latency: Duration::from_millis(0),
+            clock: Arc::new(RealClock),
+        }
+    }
+
+    pub fn with_message_queue_size(self, message_queue_size: usize) -> Self {
+        Self {
+            message_queue_size,
+            ..self
+        }
+    }
+
+    pub fn with_overflow_policy(self, overflow_policy: OverflowPolicy) -> Self {
+        Self {
+            overflow_policy,
+            ..self
+        }
+    }
+
+    pub fn with_min_level(self, min_level: LogLevel) -> Self {
+        Self { min_level, ..self }
+    }
+
+    /// Maximum amount of messages written to the sinks at once. A batch size
+    /// of 1 writes every message on its own.
+    pub fn with_batch_size(self, batch_size: usize) -> Self {
+        Self { batch_size, ..self }
+    }
+
+    /// How long the first message of a batch may wait for the batch to fill
+    /// up. With no latency, a batch only holds the messages already queued.
+    pub fn with_batch_max_latency(self, batch_max_latency: Duration) -> Self {
+        Self {
+            batch_max_latency,
+            ..self
+        }
+    }
+
+    /// Sets the clock timestamping the messages.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
+
+    /// Sets the format of the writer given to `new`.
+    pub fn with_format(mut self, format: LogFormat) -> Self {
+        let sink = self.sinks.remove(0);
+        self.sinks.insert(0, sink.with_format(format));
+        self
+    }
+
+    pub fn with_sink(mut self, sink: Sink) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+
+    pub fn start<T: 'static + Context>(self) -> (Logger<T>, LoggerHandle) {
+        let (control_sender, control_receiver) = channel::unbounded();
+        let min_level = Arc::new(AtomicUsize::new(self.min_level as usize));
+        let dropped_messages = Arc::new(AtomicUsize::new(0));
+        let (logger, recv_chan) = self.new_logger::<T>(&min_level, &dropped_messages);
+        let (external, external_chan) =
+            self.new_logger::<ExternalRecord>(&min_level, &dropped_messages);
+
+        let mut dispatcher = Dispatcher::new(self.sinks.into_iter().map(Sink::start).collect());
+        let dropped_lines = dispatcher
+            .sinks
+            .iter()
+            .map(|sink| sink.dropped_lines.clone())
+            .collect();
+        let report_dropped = self.overflow_policy == OverflowPolicy::DropAndReport;
+        let clock = self.clock.clone();
+        let batching = Batching {
+            size: self.batch_size.max(1),
+            max_latency: self.batch_max_latency,
+        };
+
+        let thread = thread::spawn(move || {
+            let (mut recv_chan, mut external_chan) = (recv_chan, external_chan);
+            let mut control_receiver = control_receiver;
+            // the thread stops once every logger, typed or external, was dropped
+            let mut connected_channels = 2;
+            let mut reported_dropped = 0;
+            loop {
+                select! {
+                    recv(recv_chan) -> received => {
+                        if !receive_batch(&mut dispatcher, &mut recv_chan, received, batching) {
+                            connected_channels -= 1;
+                        }
+                    },
+                    recv(external_chan) -> received => {
+                        if !receive_batch(&mut dispatcher, &mut external_chan, received, batching) {
+                            connected_channels -= 1;
+                        }
+                    },
+                    recv(control_receiver) -> control => {
+                        // everything queued before the request must be written first
+                        drain_queue(&mut dispatcher, &recv_chan, batching);
+                        drain_queue(&mut dispatcher, &external_chan, batching);
+                        match control {
+                            Ok(Control::Flush(done)) => {
+                                dispatcher.flush();
+                                let _ = done.send(());
+                            }
+                            Ok(Control::Shutdown) => break,
+                            Err(_) => control_receiver = channel::never(),
+                        }
+                    },
+                }
+
+                if report_dropped {
+                    reported_dropped = report_dropped_messages(
+                        &mut dispatcher,
+                        &*clock,
+                        &dropped_messages,
+                        reported_dropped,
                     );
                 }
+                if connected_channels == 0 {
+                    break;
+                }
+            }
+            if report_dropped {
+                report_dropped_messages(
+                    &mut dispatcher,
+                    &*clock,
+                    &dropped_messages,
+                    reported_dropped,
+                );
             }
+            dispatcher.shutdown();
         });
 
-        Logger(send_chan)
+        let handle = LoggerHandle {
+            control: control_sender,
+            thread,
+            dropped_lines,
+            external,
+        };
+        (logger, handle)
+    }
+
+    fn new_logger<T: Context>(
+        &self,
+        min_level: &Arc<AtomicUsize>,
+        dropped_messages: &Arc<AtomicUsize>,
+    ) -> (Logger<T>, Receiver<LogMessage<T>>) {
+        let (send_chan, recv_chan) = channel::bounded(self.message_queue_size);
+        let logger = Logger {
+            sender: send_chan,
+            receiver: match self.overflow_policy {
+                OverflowPolicy::DropOldest => Some(recv_chan.clone()),
+                _ => None,
+            },
+            overflow_policy: self.overflow_policy,
+            min_level: min_level.clone(),
+            dropped_messages: dropped_messages.clone(),
+            clock: self.clock.clone(),
+        };
+        (logger, recv_chan)
+    }
+}
+
+impl<T: Context> Logger<T> {
+    pub fn start<U: 'static + Send + Write>(writer: U, message_queue_size: usize) -> Self
+    where
+        T: 'static,
+    {
+        LoggerBuilder::new(writer)
+            .with_message_queue_size(message_queue_size)
+            .start()
+            .0
+    }
+
+    pub fn trace(&self, context: T) {
+        self.log(LogLevel::Trace, context);
+    }
+
+    pub fn debug(&self, context: T) {
+        self.log(LogLevel::Debug, context);
     }
 
     pub fn info(&self, context: T) {
-        self.0
-            .send(LogMessage::new(Utc::now(), LogLevel::Info, context))
-            .unwrap();
+        self.log(LogLevel::Info, context);
     }
 
     pub fn warning(&self, context: T) {
-        self.0
-            .send(LogMessage::new(Utc::now(), LogLevel::Warning, context))
-            .unwrap();
+        self.log(LogLevel::Warning, context);
     }
 
     pub fn error(&self, context: T) {
-        self.0
-            .send(LogMessage::new(Utc::now(), LogLevel::Error, context))
-            .unwrap();
+        self.log(LogLevel::Error, context);
+    }
+
+    /// Number of messages dropped because the queue was full, shared by all
+    /// clones of this logger.
+    pub fn dropped_messages(&self) -> usize {
+        self.dropped_messages.load(Ordering::Relaxed)
+    }
+
+    pub fn min_level(&self) -> LogLevel {
+        LogLevel::from_usize(self.min_level.load(Ordering::Relaxed))
+    }
+
+    /// Changes the minimum level of this logger and all of its clones.
+    pub fn set_min_level(&self, min_level: LogLevel) {
+        self.min_level.store(min_level as usize, Ordering::Relaxed);
+    }
+
+    /// Lets callers skip building contexts that would be filtered out anyway.
+    pub fn is_enabled(&self, level: LogLevel) -> bool {
+        level as usize >= self.min_level.load(Ordering::Relaxed)
+    }
+
+    fn log(&self, level: LogLevel, context: T) {
+        if !self.is_enabled(level) {
+            return;
+        }
+
+        let mut log_message = LogMessage::new(self.clock.now(), level, context);
+
+        // Sending only fails with a disconnected channel once the logger was
+        // shut down, the message is then dropped.
+        match self.overflow_policy {
+            OverflowPolicy::Block => {
+                let _ = self.sender.send(log_message);
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::DropAndReport => {
+                if let Err(TrySendError::Full(_)) = self.sender.try_send(log_message) {
+                    self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            OverflowPolicy::DropOldest => {
+                let receiver = self.receiver.as_ref().unwrap();
+                while let Err(TrySendError::Full(rejected)) = self.sender.try_send(log_message) {
+                    if receiver.try_recv().is_ok() {
+                        self.dropped_messages.fetch_add(1, Ordering::Relaxed);
+                    }
+                    log_message = rejected;
+                }
+            }
+        }
+    }
+}
+
+impl LoggerHandle {
+    pub fn flush(&self) {
+        request_flush(&self.control);
+    }
+
+    /// Adapter to route the messages of the `log` crate facade to this logger.
+    pub fn log_bridge(&self) -> LogBridge {
+        LogBridge {
+            logger: self.external.clone(),
+            control: self.control.clone(),
+            min_level: LogLevel::Trace,
+        }
+    }
+
+    /// Number of lines each sink missed because its queue was full, in the
+    /// order the sinks were added, starting with the writer given to the builder.
+    pub fn dropped_lines(&self) -> Vec<usize> {
+        self.dropped_lines
+            .iter()
+            .map(|dropped| dropped.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Writes the queued messages, flushes the sinks and waits for the
+    /// logger's threads to finish. Messages logged afterwards are dropped.
+    pub fn shutdown(self) {
+        let _ = self.control.send(Control::Shutdown);
+        self.thread.join().unwrap();
+    }
+}
+
+/// A message of the `log` crate facade, e.g. from a dependency.
+#[derive(Clone, Debug)]
+pub struct ExternalRecord {
+    pub target: String,
+    pub message: String,
+}
+
+impl Context for ExternalRecord {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "{}: {}", self.target, self.message)
+    }
+
+    fn write_fields(&self, fields: &mut Fields) -> fmt::Result {
+        fields.add("target", &self.target)
+    }
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Trace => LogLevel::Trace,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Warn => LogLevel::Warning,
+            log::Level::Error => LogLevel::Error,
+        }
+    }
+}
+
+/// Implements `log::Log` on top of a started logger, so that the messages of
+/// the `log` crate go through the same queue, filters and sinks as its typed
+/// messages. Created by `LoggerHandle::log_bridge`.
+///
+/// The facade's messages have their own queue: they keep their order, and
+/// their timestamps, but may be written before typed messages logged earlier.
+#[derive(Clone)]
+pub struct LogBridge {
+    logger: Logger<ExternalRecord>,
+    control: Sender<Control>,
+    min_level: LogLevel,
+}
+
+impl LogBridge {
+    /// Filters the facade's messages on top of the logger's own minimum
+    /// level, e.g. to quiet chatty dependencies.
+    pub fn with_min_level(self, min_level: LogLevel) -> Self {
+        Self { min_level, ..self }
+    }
+
+    /// Sets the bridge as the logger of the `log` crate, which can only be
+    /// done once per process.
+    pub fn install(self) -> Result<(), log::SetLoggerError> {
+        log::set_boxed_logger(Box::new(self))?;
+        // filtering is left to the bridge, whose logger's level can change
+        log::set_max_level(log::LevelFilter::Trace);
+        Ok(())
+    }
+}
+
+impl log::Log for LogBridge {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        let level = LogLevel::from(metadata.level());
+        level >= self.min_level && self.logger.is_enabled(level)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        self.logger.log(
+            record.level().into(),
+            ExternalRecord {
+                target: record.target().to_owned(),
+                message: record.args().to_string(),
+            },
+        );
+    }
+
+    fn flush(&self) {
+        request_flush(&self.control);
+    }
+}
+
+fn request_flush(control: &Sender<Control>) {
+    let (done_sender, done_receiver) = channel::bounded(1);
+    if control.send(Control::Flush(done_sender)).is_ok() {
+        let _ = done_receiver.recv();
+    }
+}
+
+/// Writes the received message along with the ones queued after it. Returns
+/// whether the channel is still connected, it is replaced by a channel that
+/// is never ready otherwise.
+fn receive_batch<T: Context>(
+    dispatcher: &mut Dispatcher,
+    receiver: &mut Receiver<LogMessage<T>>,
+    received: Result<LogMessage<T>, RecvError>,
+    batching: Batching,
+) -> bool {
+    let disconnected = match received {
+        Ok(log_message) => collect_batch(dispatcher, receiver, log_message, batching),
+        Err(_) => true,
+    };
+    dispatcher.send_batches();
+    if disconnected {
+        *receiver = channel::never();
+    }
+    !disconnected
+}
+
+/// Writes every queued message, a batch at a time.
+fn drain_queue<T: Context>(
+    dispatcher: &mut Dispatcher,
+    receiver: &Receiver<LogMessage<T>>,
+    batching: Batching,
+) {
+    for (index, log_message) in receiver.try_iter().enumerate() {
+        dispatcher.add_message(&log_message);
+        if (index + 1) % batching.size == 0 {
+            dispatcher.send_batches();
+        }
+    }
+    dispatcher.send_batches();
+}
+
+#[derive(Copy, Clone)]
+struct Batching {
+    size: usize,
+    max_latency: Duration,
+}
+
+/// Adds `first` and the messages queued after it to the batches, until the
+/// batch is full or `first` waited for the maximum latency. Returns whether
+/// every `Logger` was dropped.
+fn collect_batch<T: Context>(
+    dispatcher: &mut Dispatcher,
+    receiver: &Receiver<LogMessage<T>>,
+    first: LogMessage<T>,
+    batching: Batching,
+) -> bool {
+    let deadline = Instant::now() + batching.max_latency;
+    dispatcher.add_message(&first);
+
+    for _ in 1..batching.size {
+        let log_message = match receiver.try_recv() {
+            Ok(log_message) => log_message,
+            Err(TryRecvError::Disconnected) => return true,
+            Err(TryRecvError::Empty) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return false;
+                }
+                match receiver.recv_timeout(deadline - now) {
+                    Ok(log_message) => log_message,
+                    Err(RecvTimeoutError::Timeout) => return false,
+                    Err(RecvTimeoutError::Disconnected) => return true,
+                }
+            }
+        };
+        dispatcher.add_message(&log_message);
+    }
+    false
+}
+
+/// Reports how many messages were dropped since the last report and returns
+/// the total amount of dropped messages.
+fn report_dropped_messages(
+    dispatcher: &mut Dispatcher,
+    clock: &dyn Clock,
+    dropped_messages: &AtomicUsize,
+    reported: usize,
+) -> usize {
+    let dropped = dropped_messages.load(Ordering::Relaxed);
+    if dropped == reported {
+        return dropped;
+    }
+
+    let log_message = LogMessage::new(
+        clock.now(),
+        LogLevel::Warning,
+        DroppedMessages(dropped - reported),
+    );
+    dispatcher.add_message(&log_message);
+    dispatcher.send_batches();
+    dropped
+}
+
+struct DroppedMessages(usize);
+
+impl Context for DroppedMessages {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "logger queue was full, dropped {} messages", self.0)
+    }
+
+    fn write_fields(&self, fields: &mut Fields) -> fmt::Result {
+        fields.add("dropped", &self.0)
+    }
+}
+
+fn write_batch<U: Write + ?Sized>(writer: &mut U, batch: &[u8]) {
+    if let Err(err) = writer.write_all(batch) {
+        eprintln!(
+            "{}, logger could not write log message successfully: {}",
+            Utc::now(),
+            err
+        );
+    }
+}
+
+fn flush_writer<U: Write + ?Sized>(writer: &mut U) {
+    if let Err(err) = writer.flush() {
+        eprintln!("{}, logger could not flush its writer: {}", Utc::now(), err);
     }
 }
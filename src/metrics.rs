@@ -0,0 +1,251 @@
+// Author: Karim Elmougi
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// every power of two is split in 2^SUB_BUCKET_BITS buckets, which bounds the
+// relative error of a percentile to about 3%
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKET_COUNT: usize = 1 << SUB_BUCKET_BITS;
+const BUCKET_COUNT: usize = (64 - SUB_BUCKET_BITS as usize + 1) * SUB_BUCKET_COUNT;
+
+/// Latency histogram in nanoseconds with logarithmic buckets, in the spirit of
+/// HdrHistogram. It covers the whole `u64` range in a fixed 15 KiB, and can be
+/// recorded to from several threads at once without locking.
+pub struct Histogram {
+    counts: Box<[AtomicU64]>,
+    total_count: AtomicU64,
+    sum: AtomicU64,
+    min: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Histogram {
+            counts: (0..BUCKET_COUNT).map(|_| AtomicU64::new(0)).collect(),
+            total_count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            min: AtomicU64::new(u64::MAX),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, nanos: u64) {
+        self.counts[bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.total_count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+        self.min.fetch_min(nanos, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    pub fn record_duration(&self, duration: Duration) {
+        let nanos = duration.as_nanos();
+        self.record(if nanos > u128::from(u64::MAX) {
+            u64::MAX
+        } else {
+            nanos as u64
+        });
+    }
+
+    /// Adds the values recorded by `other`, e.g. by another thread.
+    pub fn merge(&self, other: &Histogram) {
+        for (count, other_count) in self.counts.iter().zip(other.counts.iter()) {
+            let other_count = other_count.load(Ordering::Relaxed);
+            if other_count > 0 {
+                count.fetch_add(other_count, Ordering::Relaxed);
+            }
+        }
+        self.total_count
+            .fetch_add(other.total_count.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.sum
+            .fetch_add(other.sum.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.min
+            .fetch_min(other.min.load(Ordering::Relaxed), Ordering::Relaxed);
+        self.max
+            .fetch_max(other.max.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total_count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> u64 {
+        self.sum.load(Ordering::Relaxed)
+    }
+
+    /// The value below which `percentile` percent of the values fall, `None`
+    /// until a value is recorded. It is the highest value of its bucket,
+    /// capped by the highest recorded value.
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        let total_count = self.count();
+        if total_count == 0 {
+            return None;
+        }
+        let rank = ((percentile / 100.0 * total_count as f64).ceil() as u64).max(1);
+        let max = self.max.load(Ordering::Relaxed);
+
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Some(bucket_high(index).min(max));
+            }
+        }
+        // values recorded while iterating
+        Some(max)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let count = self.count();
+        if count == 0 {
+            return Snapshot::default();
+        }
+        Snapshot {
+            count,
+            min: self.min.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+            mean: self.sum.load(Ordering::Relaxed) / count,
+            p50: self.percentile(50.0).unwrap_or(0),
+            p90: self.percentile(90.0).unwrap_or(0),
+            p99: self.percentile(99.0).unwrap_or(0),
+            p999: self.percentile(99.9).unwrap_or(0),
+        }
+    }
+
+    pub fn reset(&self) {
+        for count in self.counts.iter() {
+            count.store(0, Ordering::Relaxed);
+        }
+        self.total_count.store(0, Ordering::Relaxed);
+        self.sum.store(0, Ordering::Relaxed);
+        self.min.store(u64::MAX, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clone for Histogram {
+    fn clone(&self) -> Self {
+        let histogram = Histogram::new();
+        histogram.merge(self);
+        histogram
+    }
+}
+
+/// Summary of a `Histogram` at some point in time, all in nanoseconds.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub mean: u64,
+    pub p50: u64,
+    pub p90: u64,
+    pub p99: u64,
+    pub p999: u64,
+}
+
+impl Snapshot {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+/// Writes metrics in the text format scraped by Prometheus.
+#[derive(Default)]
+pub struct PrometheusEncoder {
+    out: String,
+}
+
+impl PrometheusEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "counter");
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: u64) {
+        self.header(name, help, "gauge");
+        let _ = writeln!(self.out, "{} {}", name, value);
+    }
+
+    /// A gauge with a value per value of `label`.
+    pub fn labeled_gauge(&mut self, name: &str, help: &str, label: &str, values: &[(&str, u64)]) {
+        self.header(name, help, "gauge");
+        for (label_value, value) in values {
+            let _ = writeln!(
+                self.out,
+                "{}{{{}=\"{}\"}} {}",
+                name, label, label_value, value
+            );
+        }
+    }
+
+    /// A histogram of nanoseconds, exported as a summary in seconds, with its
+    /// p50, p90, p99 and p99.9 as quantiles.
+    pub fn summary(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        self.header(name, help, "summary");
+        for &(quantile, percentile) in &[
+            ("0.5", 50.0),
+            ("0.9", 90.0),
+            ("0.99", 99.0),
+            ("0.999", 99.9),
+        ] {
+            match histogram.percentile(percentile) {
+                Some(nanos) => {
+                    let seconds = nanos as f64 / 1e9;
+                    let _ = writeln!(
+                        self.out,
+                        "{}{{quantile=\"{}\"}} {}",
+                        name, quantile, seconds
+                    );
+                }
+                None => {
+                    let _ = writeln!(self.out, "{}{{quantile=\"{}\"}} NaN", name, quantile);
+                }
+            }
+        }
+        let _ = writeln!(self.out, "{}_sum {}", name, histogram.sum() as f64 / 1e9);
+        let _ = writeln!(self.out, "{}_count {}", name, histogram.count());
+    }
+
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    fn header(&mut self, name: &str, help: &str, metric_type: &str) {
+        let _ = writeln!(self.out, "# HELP {} {}", name, help);
+        let _ = writeln!(self.out, "# TYPE {} {}", name, metric_type);
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKET_COUNT as u64 {
+        return value as usize;
+    }
+    let exponent = 63 - value.leading_zeros();
+    let shift = exponent - SUB_BUCKET_BITS;
+    let mantissa = (value >> shift) as usize & (SUB_BUCKET_COUNT - 1);
+    (shift as usize + 1) * SUB_BUCKET_COUNT + mantissa
+}
+
+fn bucket_high(index: usize) -> u64 {
+    if index < SUB_BUCKET_COUNT {
+        return index as u64;
+    }
+    let shift = (index / SUB_BUCKET_COUNT - 1) as u32;
+    let mantissa = (index % SUB_BUCKET_COUNT) as u64;
+    let low = (SUB_BUCKET_COUNT as u64 + mantissa) << shift;
+    low + ((1 << shift) - 1)
+}
@@ -1,14 +1,33 @@
 // Author: Karim Elmougi
 
-use crate::logger::{Context, Logger};
-use crossbeam::channel::tick;
+use crate::client::Record;
+use crate::clock::{Clock, RealClock};
+use crate::logger::{
+    Context, Fields, LogBridge, LogDestination, LogFormat, LogLevel, Logger, LoggerBuilder,
+    LoggerHandle, OverflowPolicy, RotatingFileBuilder, Sink, WriteMode, BINARY_PAYLOAD_SIZE,
+    BINARY_RECORD_SIZE,
+};
+use crate::metrics::{Histogram, PrometheusEncoder, Snapshot};
+use crate::validate::{self, LineIssue, Validator};
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use crossbeam::channel::{self, select, Receiver, Sender, TryRecvError};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{self, Write as _};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
-use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::io::{self, BufRead, BufReader, Cursor, ErrorKind, Write};
+use std::mem;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
-use tiny_http::{Method, Request, Response, Server};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// How often the blocking server threads check whether a shutdown was requested.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How long a new subscriber has to send the symbols it subscribes to.
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Copy, Clone)]
 pub enum ServerLogs {
@@ -21,46 +40,264 @@ pub enum ServerLogs {
     ClientUnreachable(SocketAddr),
     PushingToSubscriber(SocketAddr),
     AddingSubscriber(SocketAddr),
+    ShuttingDown,
+    InvalidSubscription(SocketAddr),
+    /// With the amount of duplicates dropped.
+    SortedRecords(usize),
+    /// With the OS error code, if any.
+    AcceptFailed(Option<i32>),
 }
 
 impl Context for ServerLogs {
-    fn context_string(&self) -> String {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
         match self {
-            ServerLogs::LoadingRecords => "Loading records data...".to_string(),
-            ServerLogs::StartingPushServer => "Starting push server...".to_string(),
-            ServerLogs::StartingSubscriptionServer => "Starting subscription server...".to_string(),
-            ServerLogs::StartingHttpServer => "Starting http server...".to_string(),
+            ServerLogs::LoadingRecords => out.write_str("Loading records data..."),
+            ServerLogs::StartingPushServer => out.write_str("Starting push server..."),
+            ServerLogs::StartingSubscriptionServer => {
+                out.write_str("Starting subscription server...")
+            }
+            ServerLogs::StartingHttpServer => out.write_str("Starting http server..."),
             ServerLogs::MaxSubscribersInsufficient => {
-                "maximum number of connections is insufficient".to_string()
+                out.write_str("maximum number of connections is insufficient")
+            }
+            ServerLogs::MissedPushDeadline => {
+                out.write_str("missed a deadline on subscription push")
             }
-            ServerLogs::MissedPushDeadline => "missed a deadline on subscription push".to_string(),
-            ServerLogs::ClientUnreachable(addr) => format!("could not reach client: {}", addr),
+            ServerLogs::ClientUnreachable(addr) => write!(out, "could not reach client: {}", addr),
             ServerLogs::PushingToSubscriber(addr) => {
-                format!("pushing data to subscriber: {}", addr)
+                write!(out, "pushing data to subscriber: {}", addr)
             }
-            ServerLogs::AddingSubscriber(addr) => format!("adding subscriber: {}", addr),
+            ServerLogs::AddingSubscriber(addr) => write!(out, "adding subscriber: {}", addr),
+            ServerLogs::ShuttingDown => out.write_str("Shutting down..."),
+            ServerLogs::InvalidSubscription(addr) => {
+                write!(out, "invalid subscription from: {}", addr)
+            }
+            ServerLogs::SortedRecords(duplicate_count) => write!(
+                out,
+                "records were not in increasing timestamp order, sorted them and dropped {} duplicates",
+                duplicate_count
+            ),
+            ServerLogs::AcceptFailed(Some(code)) => write!(
+                out,
+                "could not accept a subscriber: {}",
+                io::Error::from_raw_os_error(*code)
+            ),
+            ServerLogs::AcceptFailed(None) => out.write_str("could not accept a subscriber"),
         }
     }
+
+    fn write_fields(&self, fields: &mut Fields) -> fmt::Result {
+        match self {
+            ServerLogs::ClientUnreachable(addr) => fields.add("client", addr),
+            ServerLogs::PushingToSubscriber(addr)
+            | ServerLogs::AddingSubscriber(addr)
+            | ServerLogs::InvalidSubscription(addr) => fields.add("subscriber", addr),
+            ServerLogs::SortedRecords(duplicate_count) => fields.add("duplicates", duplicate_count),
+            ServerLogs::AcceptFailed(Some(code)) => fields.add("os_error", code),
+            _ => Ok(()),
+        }
+    }
+
+    fn event_id(&self) -> u16 {
+        match self {
+            ServerLogs::LoadingRecords => 1,
+            ServerLogs::StartingPushServer => 2,
+            ServerLogs::StartingSubscriptionServer => 3,
+            ServerLogs::StartingHttpServer => 4,
+            ServerLogs::MaxSubscribersInsufficient => 5,
+            ServerLogs::MissedPushDeadline => 6,
+            ServerLogs::ClientUnreachable(_) => 7,
+            ServerLogs::PushingToSubscriber(_) => 8,
+            ServerLogs::AddingSubscriber(_) => 9,
+            ServerLogs::ShuttingDown => 10,
+            ServerLogs::InvalidSubscription(_) => 11,
+            ServerLogs::SortedRecords(_) => 12,
+            ServerLogs::AcceptFailed(_) => 13,
+        }
+    }
+
+    /// The address of the client as an IPv6 address followed by the port, the
+    /// amount of duplicates, or whether there is an OS error code followed by it.
+    fn write_payload(&self, payload: &mut [u8; BINARY_PAYLOAD_SIZE]) {
+        match self {
+            ServerLogs::ClientUnreachable(addr)
+            | ServerLogs::PushingToSubscriber(addr)
+            | ServerLogs::AddingSubscriber(addr)
+            | ServerLogs::InvalidSubscription(addr) => {
+                let ip = match addr.ip() {
+                    IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                    IpAddr::V6(ip) => ip,
+                };
+                payload[..16].copy_from_slice(&ip.octets());
+                payload[16..18].copy_from_slice(&addr.port().to_le_bytes());
+            }
+            ServerLogs::SortedRecords(duplicate_count) => {
+                payload[..8].copy_from_slice(&(*duplicate_count as u64).to_le_bytes());
+            }
+            ServerLogs::AcceptFailed(Some(code)) => {
+                payload[0] = 1;
+                payload[4..8].copy_from_slice(&code.to_le_bytes());
+            }
+            _ => (),
+        }
+    }
+}
+
+impl ServerLogs {
+    /// The message logged with `event_id` and `payload` in a binary log, `None`
+    /// for an unknown event id.
+    pub fn from_binary(event_id: u16, payload: &[u8; BINARY_PAYLOAD_SIZE]) -> Option<Self> {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&payload[..16]);
+        let ip = Ipv6Addr::from(octets);
+        let ip = match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => IpAddr::V6(ip),
+        };
+        let addr = SocketAddr::new(ip, u16::from_le_bytes([payload[16], payload[17]]));
+
+        Some(match event_id {
+            1 => ServerLogs::LoadingRecords,
+            2 => ServerLogs::StartingPushServer,
+            3 => ServerLogs::StartingSubscriptionServer,
+            4 => ServerLogs::StartingHttpServer,
+            5 => ServerLogs::MaxSubscribersInsufficient,
+            6 => ServerLogs::MissedPushDeadline,
+            7 => ServerLogs::ClientUnreachable(addr),
+            8 => ServerLogs::PushingToSubscriber(addr),
+            9 => ServerLogs::AddingSubscriber(addr),
+            10 => ServerLogs::ShuttingDown,
+            11 => ServerLogs::InvalidSubscription(addr),
+            12 => {
+                let mut count = [0; 8];
+                count.copy_from_slice(&payload[..8]);
+                ServerLogs::SortedRecords(u64::from_le_bytes(count) as usize)
+            }
+            13 => ServerLogs::AcceptFailed(if payload[0] == 1 {
+                Some(i32::from_le_bytes([
+                    payload[4], payload[5], payload[6], payload[7],
+                ]))
+            } else {
+                None
+            }),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum ServerError {
+    Log(io::Error),
+    Data(String, csv::Error),
+    InvalidRecord(String, LineIssue),
+    NoRecords(String),
+    Bind(SocketAddr, io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServerError::Log(err) => write!(f, "could not open server log: {}", err),
+            ServerError::Data(path, err) => write!(f, "could not read data file {}: {}", path, err),
+            ServerError::InvalidRecord(path, issue) => {
+                write!(f, "invalid data file {}: {}", path, issue)
+            }
+            ServerError::NoRecords(path) => write!(f, "no records in data file {}", path),
+            ServerError::Bind(addr, err) => write!(f, "could not listen on {}: {}", addr, err),
+        }
+    }
+}
+
+impl Error for ServerError {}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayMode {
+    Once,
+    Loop,
+}
+
+struct Instrument {
+    symbol: String,
+    data_file: String,
+    max_records_amount: Option<usize>,
 }
 
 pub struct ServerBuilder {
     data_file: String,
+    symbol: String,
+    // replayed along the first instrument
+    other_instruments: Vec<Instrument>,
+    bind_address: IpAddr,
     http_port: u16,
+    push_port: u16,
     period: u64,
+    replay_mode: ReplayMode,
     max_subscriber_count: usize,
+    log_destination: LogDestination,
+    log_sinks: Vec<Sink>,
     logger_queue_size: usize,
+    log_overflow_policy: OverflowPolicy,
+    log_batch_size: usize,
+    log_batch_max_latency: Duration,
+    log_level: LogLevel,
+    log_format: LogFormat,
     max_records_amount: Option<usize>,
+    clock: Arc<dyn Clock>,
 }
 
 impl ServerBuilder {
     pub fn new(data_file: &str) -> Self {
         ServerBuilder {
             data_file: data_file.to_owned(),
+            symbol: "BTCUSD".to_owned(),
+            other_instruments: Vec::new(),
+            bind_address: IpAddr::V4(Ipv4Addr::LOCALHOST),
             http_port: 80,
+            push_port: 0,
             period: 1000,
+            replay_mode: ReplayMode::Once,
             max_subscriber_count: 10,
+            log_destination: LogDestination::File("server_log.csv".to_owned(), WriteMode::Truncate),
+            log_sinks: Vec::new(),
             logger_queue_size: 100,
+            log_overflow_policy: OverflowPolicy::Block,
+            log_batch_size: 64,
+            log_batch_max_latency: Duration::from_millis(0),
+            log_level: LogLevel::Info,
+            log_format: LogFormat::Plain,
             max_records_amount: None,
+            clock: Arc::new(RealClock),
+        }
+    }
+
+    pub fn with_symbol(self, symbol: &str) -> Self {
+        Self {
+            symbol: symbol.to_owned(),
+            ..self
+        }
+    }
+
+    /// Replays another instrument at the same time as the first one, which
+    /// clients may subscribe to on the same connection.
+    pub fn with_instrument(
+        mut self,
+        symbol: &str,
+        data_file: &str,
+        max_records_amount: Option<usize>,
+    ) -> Self {
+        self.other_instruments.push(Instrument {
+            symbol: symbol.to_owned(),
+            data_file: data_file.to_owned(),
+            max_records_amount,
+        });
+        self
+    }
+
+    pub fn with_bind_address(self, bind_address: IpAddr) -> Self {
+        Self {
+            bind_address,
+            ..self
         }
     }
 
@@ -68,10 +305,23 @@ impl ServerBuilder {
         Self { http_port, ..self }
     }
 
+    /// A push port of 0 lets the OS pick a free port, which is then advertised
+    /// to clients through `/subscribe`.
+    pub fn with_push_port(self, push_port: u16) -> Self {
+        Self { push_port, ..self }
+    }
+
     pub fn with_period(self, period: u64) -> Self {
         Self { period, ..self }
     }
 
+    pub fn with_replay_mode(self, replay_mode: ReplayMode) -> Self {
+        Self {
+            replay_mode,
+            ..self
+        }
+    }
+
     pub fn with_max_subscriber_count(self, max_subscriber_count: usize) -> Self {
         Self {
             max_subscriber_count,
@@ -79,6 +329,33 @@ impl ServerBuilder {
         }
     }
 
+    pub fn with_log_file(self, path: &str, mode: WriteMode) -> Self {
+        Self {
+            log_destination: LogDestination::File(path.to_owned(), mode),
+            ..self
+        }
+    }
+
+    pub fn with_log_rotating_file(self, rotating_file: RotatingFileBuilder) -> Self {
+        Self {
+            log_destination: LogDestination::RotatingFile(rotating_file),
+            ..self
+        }
+    }
+
+    pub fn with_log_writer<W: 'static + Write + Send>(self, writer: W) -> Self {
+        Self {
+            log_destination: LogDestination::Writer(Box::new(writer)),
+            ..self
+        }
+    }
+
+    /// Adds a sink written to alongside the log file or writer.
+    pub fn with_log_sink(mut self, sink: Sink) -> Self {
+        self.log_sinks.push(sink);
+        self
+    }
+
     pub fn with_logger_queue_size(self, logger_queue_size: usize) -> Self {
         Self {
             logger_queue_size,
@@ -86,6 +363,30 @@ impl ServerBuilder {
         }
     }
 
+    pub fn with_log_overflow_policy(self, log_overflow_policy: OverflowPolicy) -> Self {
+        Self {
+            log_overflow_policy,
+            ..self
+        }
+    }
+
+    /// See `LoggerBuilder::with_batch_size` and `LoggerBuilder::with_batch_max_latency`.
+    pub fn with_log_batching(self, log_batch_size: usize, log_batch_max_latency: Duration) -> Self {
+        Self {
+            log_batch_size,
+            log_batch_max_latency,
+            ..self
+        }
+    }
+
+    pub fn with_log_level(self, log_level: LogLevel) -> Self {
+        Self { log_level, ..self }
+    }
+
+    pub fn with_log_format(self, log_format: LogFormat) -> Self {
+        Self { log_format, ..self }
+    }
+
     pub fn with_max_records_amount(self, max_records_amount: Option<usize>) -> Self {
         Self {
             max_records_amount,
@@ -93,153 +394,860 @@ impl ServerBuilder {
         }
     }
 
-    pub fn build_and_start(self) -> JoinHandle<()> {
-        let logger = Logger::start(File::create("server_log.csv").unwrap(), self.logger_queue_size);
+    /// Sets the clock driving the replay, the pushes and the log timestamps.
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self { clock, ..self }
+    }
 
-        logger.info(ServerLogs::LoadingRecords);
-        let records = Arc::new(load_data(&self.data_file, self.max_records_amount));
-        let streams = Arc::new(Mutex::new(Vec::with_capacity(self.max_subscriber_count)));
+    /// Fails if the log can't be opened or a data file can't be loaded, see
+    /// `load_data`.
+    pub fn build_and_start(mut self) -> Result<ServerHandle, ServerError> {
+        let log_destination = match mem::replace(&mut self.log_destination, LogDestination::Stdout)
+        {
+            // binary records may contain newline bytes
+            LogDestination::RotatingFile(rotating_file) if self.log_format == LogFormat::Binary => {
+                LogDestination::RotatingFile(
+                    rotating_file.with_record_size(Some(BINARY_RECORD_SIZE)),
+                )
+            }
+            log_destination => log_destination,
+        };
+        let log_writer = log_destination.open().map_err(ServerError::Log)?;
+        let mut logger_builder = LoggerBuilder::new(log_writer)
+            .with_message_queue_size(self.logger_queue_size)
+            .with_overflow_policy(self.log_overflow_policy)
+            .with_batch_size(self.log_batch_size)
+            .with_batch_max_latency(self.log_batch_max_latency)
+            .with_min_level(self.log_level)
+            .with_format(self.log_format)
+            .with_clock(self.clock.clone());
+        for sink in self.log_sinks.drain(..) {
+            logger_builder = logger_builder.with_sink(sink);
+        }
+        let (logger, logger_handle) = logger_builder.start();
 
-        let (_, push_port) = start_subscription_server(&self, logger.clone(), streams.clone());
+        logger.info(ServerLogs::LoadingRecords);
+        let first_instrument = Instrument {
+            symbol: self.symbol.clone(),
+            data_file: self.data_file.clone(),
+            max_records_amount: self.max_records_amount,
+        };
+        let records: Result<Vec<_>, _> = Some(&first_instrument)
+            .into_iter()
+            .chain(&self.other_instruments)
+            .map(|instrument| load_data(&instrument.data_file, instrument.max_records_amount))
+            .collect();
+        let records: Vec<_> = match records {
+            Ok(records) => records,
+            Err(err) => {
+                logger_handle.shutdown();
+                return Err(err);
+            }
+        };
+        for data in records.iter().filter(|data| data.reordered) {
+            logger.warning(ServerLogs::SortedRecords(data.duplicate_count));
+        }
+        let replays: Vec<_> = Some(&first_instrument)
+            .into_iter()
+            .chain(&self.other_instruments)
+            .zip(records)
+            .map(|(instrument, records)| {
+                Replay::new(
+                    &instrument.symbol,
+                    records.records,
+                    self.period,
+                    self.replay_mode,
+                    self.clock.clone(),
+                )
+            })
+            .collect();
+        let (listener, http_server) = match bind(&self) {
+            Ok(sockets) => sockets,
+            Err(err) => {
+                logger_handle.shutdown();
+                return Err(err);
+            }
+        };
+        let replays = Arc::new(replays);
+        let subscribers = Arc::new(Mutex::new(Vec::with_capacity(self.max_subscriber_count)));
+        let (shutdown_sender, shutdown_receiver) = channel::bounded(0);
 
-        let start_time = Instant::now();
-        let http_server_handle = start_http_server(
+        let (subscription_server_handle, push_port) = start_subscription_server(
+            &self,
+            listener,
+            logger.clone(),
+            replays.clone(),
+            subscribers.clone(),
+            shutdown_receiver.clone(),
+        );
+        let metrics = Arc::new(ServerMetrics::new());
+        let (http_server_handle, http_addr) = start_http_server(
             logger.clone(),
-            self.http_port,
+            http_server,
             push_port,
-            start_time,
-            self.period,
-            records.clone(),
+            replays.clone(),
+            subscribers.clone(),
+            metrics.clone(),
+            shutdown_receiver.clone(),
         );
 
-        start_push_server(
+        let push_server_handle = start_push_server(
             logger.clone(),
-            start_time,
-            self.period,
-            records.clone(),
-            streams.clone(),
+            replays,
+            subscribers.clone(),
+            metrics.clone(),
+            shutdown_receiver,
         );
 
-        http_server_handle
+        Ok(ServerHandle {
+            http_addr,
+            push_port,
+            logger,
+            logger_handle,
+            subscribers,
+            metrics,
+            shutdown_sender,
+            threads: vec![
+                subscription_server_handle,
+                http_server_handle,
+                push_server_handle,
+            ],
+        })
     }
 }
 
-fn start_http_server(
-    logger: Logger<ServerLogs>,
-    http_port: u16,
+pub struct ServerHandle {
+    http_addr: SocketAddr,
     push_port: u16,
+    logger: Logger<ServerLogs>,
+    logger_handle: LoggerHandle,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    metrics: Arc<ServerMetrics>,
+    shutdown_sender: Sender<()>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
+
+    pub fn push_port(&self) -> u16 {
+        self.push_port
+    }
+
+    /// What `GET /metrics` reports.
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    /// Adapter to route the messages of the `log` crate facade to the server's log.
+    pub fn log_bridge(&self) -> LogBridge {
+        self.logger_handle.log_bridge()
+    }
+
+    /// Stops accepting requests and subscribers, closes every subscriber's
+    /// stream, which they see as the end of the stream, and waits for the
+    /// server's threads to finish. The server logs are flushed before returning.
+    pub fn shutdown(self) {
+        self.logger.info(ServerLogs::ShuttingDown);
+
+        drop(self.shutdown_sender);
+        for thread in self.threads {
+            thread.join().unwrap();
+        }
+
+        for subscriber in self.subscribers.lock().unwrap().drain(..) {
+            let _ = subscriber.stream.shutdown(Shutdown::Both);
+        }
+
+        self.logger_handle.shutdown();
+    }
+}
+
+fn is_shutting_down(shutdown: &Receiver<()>) -> bool {
+    shutdown.try_recv() == Err(TryRecvError::Disconnected)
+}
+
+/// Replays an instrument's records, advancing by one record every `period` μs.
+pub struct Replay {
+    symbol: String,
+    // in increasing timestamp order
+    records: Vec<Record>,
+    clock: Arc<dyn Clock>,
     start_time: Instant,
     period: u64,
-    records: Arc<Vec<String>>,
-) -> JoinHandle<()> {
+    mode: ReplayMode,
+}
+
+impl Replay {
+    pub fn new(
+        symbol: &str,
+        records: Vec<Record>,
+        period: u64,
+        mode: ReplayMode,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Replay {
+            symbol: symbol.to_owned(),
+            records,
+            start_time: clock.instant(),
+            clock,
+            period,
+            mode,
+        }
+    }
+
+    /// Returns `None` once a `ReplayMode::Once` replay went past its last record.
+    pub fn current(&self) -> Option<&Record> {
+        self.records.get(self.index()?)
+    }
+
+    /// Index of the current record, `None` when `current` is.
+    pub fn index(&self) -> Option<usize> {
+        let i = (self.elapsed().as_micros() / u128::from(self.period)) as usize;
+        let i = match self.mode {
+            ReplayMode::Once => i,
+            ReplayMode::Loop => i.checked_rem(self.records.len())?,
+        };
+        if i < self.records.len() {
+            Some(i)
+        } else {
+            None
+        }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.clock.instant().duration_since(self.start_time)
+    }
+
+    pub fn record_count(&self) -> usize {
+        self.records.len()
+    }
+
+    /// The last record at or before `timestamp`, in milliseconds.
+    pub fn record_at(&self, timestamp: i64) -> Option<&Record> {
+        let index = match self
+            .records
+            .binary_search_by_key(&timestamp, |record| record.timestamp)
+        {
+            Ok(index) => index,
+            Err(index) => index.checked_sub(1)?,
+        };
+        self.records.get(index)
+    }
+
+    /// The record as sent to subscribers, in the format of the data file. Prices
+    /// and volumes are written back from their parsed values, so they may not
+    /// be written as in the file, e.g. `3600` for `3600.00`.
+    pub fn encode(&self, record: &Record, out: &mut String) {
+        // the date is left empty for timestamps chrono can't represent
+        let date = Utc.timestamp_millis_opt(record.timestamp).single();
+        let _ = write!(
+            out,
+            "{},{},{},{},{},{},{},{}",
+            record.timestamp,
+            date.map(|date| date.format("%Y-%m-%d %H:%M:%S").to_string())
+                .unwrap_or_default(),
+            self.symbol,
+            record.open,
+            record.high,
+            record.low,
+            record.close,
+            record.volume
+        );
+    }
+
+    fn time_range(&self) -> (Option<DateTime<Utc>>, Option<DateTime<Utc>>) {
+        let record_time = |record: &Record| Utc.timestamp_millis_opt(record.timestamp).single();
+        (
+            self.records.first().and_then(record_time),
+            self.records.last().and_then(record_time),
+        )
+    }
+}
+
+/// A connection records are pushed to, with the instruments it subscribed to.
+pub struct Subscriber {
+    stream: TcpStream,
+    // indices of the instruments' replays
+    instruments: Vec<usize>,
+}
+
+impl Subscriber {
+    /// `instruments` are indices in the replays passed to `push_data`.
+    pub fn new(stream: TcpStream, instruments: Vec<usize>) -> Self {
+        Subscriber {
+            stream,
+            instruments,
+        }
+    }
+}
+
+/// Indices of the replays of a comma-separated list of symbols, `None` if a
+/// symbol is unknown or none is given.
+fn parse_subscription(replays: &[Replay], symbols: &str) -> Option<Vec<usize>> {
+    let mut instruments = Vec::new();
+    for symbol in symbols.trim().split(',') {
+        let index = replays.iter().position(|replay| replay.symbol == symbol)?;
+        if !instruments.contains(&index) {
+            instruments.push(index);
+        }
+    }
+    Some(instruments)
+}
+
+/// Body of `GET /status`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServerStatus {
+    pub uptime_secs: f64,
+    pub replay_mode: ReplayMode,
+    /// In microseconds.
+    pub period: u64,
+    pub replays: Vec<ReplayStatus>,
+    pub subscribers: Vec<SubscriberStatus>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ReplayStatus {
+    pub symbol: String,
+    /// `None` once the replay is finished.
+    pub index: Option<usize>,
+    pub record_count: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SubscriberStatus {
+    pub address: String,
+    pub symbols: Vec<String>,
+}
+
+/// An element of the body of `GET /instruments`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InstrumentInfo {
+    pub symbol: String,
+    pub record_count: usize,
+    /// RFC 3339 times of the first and last records.
+    pub start_time: Option<String>,
+    pub end_time: Option<String>,
+    /// Lowest and highest prices over every record.
+    pub low: Option<f32>,
+    pub high: Option<f32>,
+    pub volume: f64,
+}
+
+/// Counters of the push thread, updated while the server runs.
+#[derive(Default)]
+pub struct ServerMetrics {
+    records_pushed: AtomicU64,
+    bytes_written: AtomicU64,
+    dropped_subscribers: AtomicU64,
+    missed_deadlines: AtomicU64,
+    push_latencies: Histogram,
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records written to a subscriber, counted once per subscriber.
+    pub fn records_pushed(&self) -> u64 {
+        self.records_pushed.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped_subscribers(&self) -> u64 {
+        self.dropped_subscribers.load(Ordering::Relaxed)
+    }
+
+    /// Pushes that ended after the next one was due.
+    pub fn missed_deadlines(&self) -> u64 {
+        self.missed_deadlines.load(Ordering::Relaxed)
+    }
+
+    /// Time from each tick of the push period until the record was written
+    /// to every subscriber, which includes the time the push thread woke up late.
+    pub fn push_latencies(&self) -> Snapshot {
+        self.push_latencies.snapshot()
+    }
+
+    fn to_prometheus(&self, subscriber_count: usize, replays: &[Replay]) -> String {
+        let mut encoder = PrometheusEncoder::new();
+        encoder.gauge(
+            "hft_subscribers",
+            "Number of connected subscribers.",
+            subscriber_count as u64,
+        );
+        encoder.counter(
+            "hft_records_pushed_total",
+            "Records written to a subscriber.",
+            self.records_pushed(),
+        );
+        encoder.counter(
+            "hft_bytes_written_total",
+            "Bytes written to the subscribers.",
+            self.bytes_written(),
+        );
+        encoder.counter(
+            "hft_dropped_subscribers_total",
+            "Subscribers dropped after a failed write.",
+            self.dropped_subscribers(),
+        );
+        encoder.counter(
+            "hft_missed_deadlines_total",
+            "Pushes that ended after the next one was due.",
+            self.missed_deadlines(),
+        );
+        let replay_indices: Vec<_> = replays
+            .iter()
+            .filter_map(|replay| Some((replay.symbol.as_str(), replay.index()? as u64)))
+            .collect();
+        encoder.labeled_gauge(
+            "hft_replay_index",
+            "Index of the record being replayed, until the replay is finished.",
+            "symbol",
+            &replay_indices,
+        );
+        encoder.summary(
+            "hft_push_latency_seconds",
+            "Time from a tick of the push period until every subscriber was written to.",
+            &self.push_latencies,
+        );
+        encoder.finish()
+    }
+}
+
+/// Listens on the push and http ports before any thread is started, so that a
+/// port in use fails the start rather than leaving a half-started server.
+fn bind(builder: &ServerBuilder) -> Result<(TcpListener, Server), ServerError> {
+    let push_addr = SocketAddr::new(builder.bind_address, builder.push_port);
+    let listener = TcpListener::bind(push_addr)
+        .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+        .map_err(|err| ServerError::Bind(push_addr, err))?;
+
+    let http_addr = SocketAddr::new(builder.bind_address, builder.http_port);
+    let server = Server::http(http_addr).map_err(|err| {
+        let err = match err.downcast::<io::Error>() {
+            Ok(err) => *err,
+            Err(err) => io::Error::other(err),
+        };
+        ServerError::Bind(http_addr, err)
+    })?;
+    Ok((listener, server))
+}
+
+fn start_http_server(
+    logger: Logger<ServerLogs>,
+    server: Server,
+    push_port: u16,
+    replays: Arc<Vec<Replay>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    metrics: Arc<ServerMetrics>,
+    shutdown: Receiver<()>,
+) -> (JoinHandle<()>, SocketAddr) {
     logger.info(ServerLogs::StartingHttpServer);
 
-    let server = Server::http((Ipv4Addr::LOCALHOST, http_port)).unwrap();
+    let http_addr = server.server_addr();
 
-    thread::spawn(move || {
-        for request in server.incoming_requests() {
-            handle_request(
-                logger.clone(),
-                request,
-                start_time,
-                &records,
-                push_port,
-                period,
-            );
+    let handle = thread::spawn(move || {
+        let state = HttpState {
+            push_port,
+            replays,
+            subscribers,
+            metrics,
+        };
+        while !is_shutting_down(&shutdown) {
+            if let Ok(Some(request)) = server.recv_timeout(SHUTDOWN_POLL_INTERVAL) {
+                handle_request(logger.clone(), request, &state);
+            }
         }
-    })
+    });
+
+    (handle, http_addr)
 }
 
 fn start_subscription_server(
     builder: &ServerBuilder,
+    listener: TcpListener,
     logger: Logger<ServerLogs>,
-    streams: Arc<Mutex<Vec<TcpStream>>>,
+    replays: Arc<Vec<Replay>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    shutdown: Receiver<()>,
 ) -> (JoinHandle<()>, u16) {
     logger.info(ServerLogs::StartingSubscriptionServer);
 
-    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
     let push_port = listener.local_addr().unwrap().port();
     let max_subscriber_count = builder.max_subscriber_count;
-    let write_timeout = Duration::from_micros(builder.period / builder.max_subscriber_count as u64);
+    // a zero timeout is refused by `set_write_timeout`
+    let write_timeout = Duration::from_micros(builder.period / builder.max_subscriber_count as u64)
+        .max(Duration::from_micros(1));
 
     let handle = thread::spawn(move || {
-        for stream in listener.incoming() {
-            if let Ok(stream) = stream {
-                let mut v = streams.lock().unwrap();
-                if v.len() <= max_subscriber_count {
-                    logger.info(ServerLogs::AddingSubscriber(stream.peer_addr().unwrap()));
-                    stream.set_write_timeout(Some(write_timeout)).unwrap();
-                    stream.set_nonblocking(true).unwrap();
-                    v.push(stream);
-                } else {
-                    logger.warning(ServerLogs::MaxSubscribersInsufficient);
+        // a silent client would otherwise hold up the other subscriptions and
+        // the shutdown until its timeout, so each one is read on its own thread
+        let mut handshakes: Vec<(JoinHandle<()>, TcpStream)> = Vec::new();
+        while !is_shutting_down(&shutdown) {
+            handshakes.retain(|(thread, _)| !thread.is_finished());
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    let pending = match stream.try_clone() {
+                        Ok(pending) => pending,
+                        Err(_) => continue,
+                    };
+                    let logger = logger.clone();
+                    let replays = replays.clone();
+                    let subscribers = subscribers.clone();
+                    let shutdown = shutdown.clone();
+                    let thread = thread::spawn(move || {
+                        let instruments = read_subscription(&stream, &replays);
+                        // checked under the lock, so that no subscriber is
+                        // added once the shutdown drained them
+                        let mut v = subscribers.lock().unwrap();
+                        if is_shutting_down(&shutdown) {
+                            return;
+                        }
+                        let instruments = match instruments {
+                            Some(instruments) => instruments,
+                            None => {
+                                logger.warning(ServerLogs::InvalidSubscription(addr));
+                                return;
+                            }
+                        };
+                        if v.len() < max_subscriber_count {
+                            logger.info(ServerLogs::AddingSubscriber(addr));
+                            stream.set_write_timeout(Some(write_timeout)).unwrap();
+                            stream.set_nonblocking(true).unwrap();
+                            v.push(Subscriber::new(stream, instruments));
+                        } else {
+                            logger.warning(ServerLogs::MaxSubscribersInsufficient);
+                        }
+                    });
+                    handshakes.push((thread, pending));
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL)
+                }
+                Err(err) => {
+                    logger.error(ServerLogs::AcceptFailed(err.raw_os_error()));
+                    thread::sleep(SHUTDOWN_POLL_INTERVAL)
                 }
             }
         }
+
+        // wakes up the handshakes still waiting for their subscription
+        for (_, stream) in &handshakes {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        for (thread, _) in handshakes {
+            thread.join().unwrap();
+        }
     });
 
     (handle, push_port)
 }
 
+/// A new subscriber first sends the comma-separated symbols it subscribes to,
+/// on a line.
+fn read_subscription(stream: &TcpStream, replays: &[Replay]) -> Option<Vec<usize>> {
+    // accepted streams inherit the listener's non-blocking mode on some systems
+    stream.set_nonblocking(false).ok()?;
+    stream.set_read_timeout(Some(SUBSCRIPTION_TIMEOUT)).ok()?;
+    let mut line = String::new();
+    // the subscriber waits for records after the line, nothing is buffered past it
+    BufReader::new(stream).read_line(&mut line).ok()?;
+    parse_subscription(replays, &line)
+}
+
 fn start_push_server(
     logger: Logger<ServerLogs>,
-    start_time: Instant,
-    period: u64,
-    records: Arc<Vec<String>>,
-    streams: Arc<Mutex<Vec<TcpStream>>>,
+    replays: Arc<Vec<Replay>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    metrics: Arc<ServerMetrics>,
+    shutdown: Receiver<()>,
 ) -> JoinHandle<()> {
     logger.info(ServerLogs::StartingPushServer);
 
     thread::spawn(move || {
-        let period_duration = Duration::from_micros(period);
-        let ticker = tick(period_duration);
-        while let Ok(_wake_time) = ticker.recv() {
-            push_data(&logger, &streams, start_time, period, &records);
+        // every replay shares the period and clock of the server
+        let clock = replays[0].clock.clone();
+        let period_duration = Duration::from_micros(replays[0].period);
+        let ticker = clock.ticker(period_duration);
+        loop {
+            select! {
+                recv(ticker) -> tick => {
+                    push_data(&logger, &subscribers, &replays, &metrics);
+                    if let Ok(tick) = tick {
+                        let latency = clock.instant().saturating_duration_since(tick);
+                        metrics.push_latencies.record_duration(latency);
+                        if latency > period_duration {
+                            metrics.missed_deadlines.fetch_add(1, Ordering::Relaxed);
+                            logger.warning(ServerLogs::MissedPushDeadline);
+                        }
+                    }
+                }
+                recv(shutdown) -> _ => break,
+            }
         }
     })
 }
 
+/// Pushes the current record of each instrument to its subscribers, one line
+/// per record: the instrument's symbol, the record and the time it is sent, in
+/// nanoseconds since the Unix epoch. Subscribers that can't be written to are
+/// dropped.
 pub fn push_data(
-    _logger: &Logger<ServerLogs>,
-    streams: &Mutex<Vec<TcpStream>>,
-    start_time: Instant,
-    period: u64,
-    data: &[String],
+    logger: &Logger<ServerLogs>,
+    subscribers: &Mutex<Vec<Subscriber>>,
+    replays: &[Replay],
+    metrics: &ServerMetrics,
 ) {
-    let mut streams = streams.lock().unwrap();
-    let current_data = get_current_data(start_time, period, data).as_bytes();
-    streams.retain(|mut stream| {
-        let result = stream.write_all(&current_data);
-        result.is_ok()
+    let records: Vec<_> = replays
+        .iter()
+        .map(|replay| {
+            let mut line = String::with_capacity(128);
+            replay.encode(replay.current()?, &mut line);
+            Some(line)
+        })
+        .collect();
+    if records.iter().all(Option::is_none) {
+        return;
+    }
+    let clock = &replays[0].clock;
+    let mut message = String::with_capacity(256);
+    let log_pushes = logger.is_enabled(LogLevel::Debug);
+    let mut subscribers = subscribers.lock().unwrap();
+    subscribers.retain(|subscriber| {
+        message.clear();
+        let sent_at = clock.now().timestamp_nanos();
+        let mut record_count = 0;
+        for &i in &subscriber.instruments {
+            if let Some(record) = &records[i] {
+                let _ = writeln!(message, "{},{},{}", replays[i].symbol, record, sent_at);
+                record_count += 1;
+            }
+        }
+        if record_count == 0 {
+            return true;
+        }
+
+        let mut stream = &subscriber.stream;
+        if log_pushes {
+            if let Ok(addr) = stream.peer_addr() {
+                logger.debug(ServerLogs::PushingToSubscriber(addr));
+            }
+        }
+        match stream.write_all(message.as_bytes()) {
+            Ok(()) => {
+                metrics
+                    .records_pushed
+                    .fetch_add(record_count, Ordering::Relaxed);
+                metrics
+                    .bytes_written
+                    .fetch_add(message.len() as u64, Ordering::Relaxed);
+                true
+            }
+            Err(_) => {
+                metrics.dropped_subscribers.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
     });
 }
 
-fn load_data(filename: &str, max_records_amount: Option<usize>) -> Vec<String> {
-    let file = File::open(filename).unwrap_or_else(|_| panic!("invalid filename: {}", filename));
-    let reader = BufReader::new(&file);
+/// The records of a data file, in increasing timestamp order.
+#[derive(Debug)]
+pub struct LoadedData {
+    pub records: Vec<Record>,
+    /// The file wasn't in increasing timestamp order, e.g. a newest first export.
+    pub reordered: bool,
+    /// Records dropped for having the timestamp of an earlier one.
+    pub duplicate_count: usize,
+}
+
+/// Reads the records of a data file, a CSV file with a header. Every record is
+/// checked as by the `validate` tool, which can clean a file the server refuses,
+/// except for the order: records are sorted by timestamp, keeping the first one
+/// of each timestamp. `max_records_amount` counts from the start of the file.
+pub fn load_data(path: &str, max_records_amount: Option<usize>) -> Result<LoadedData, ServerError> {
+    let file = File::open(path).map_err(|err| ServerError::Data(path.to_owned(), err.into()))?;
+    let mut reader = validate::reader(file);
+    let mut validator = Validator::new().with_order_check(false);
+    let mut records = Vec::new();
 
-    let lines = reader.lines().skip(1).map(|result| result.unwrap() + "\n");
+    for result in reader
+        .records()
+        .take(max_records_amount.unwrap_or(usize::MAX))
+    {
+        let csv_record = result.map_err(|err| ServerError::Data(path.to_owned(), err))?;
+        let record = validator
+            .check_record(&csv_record)
+            .map_err(|issue| ServerError::InvalidRecord(path.to_owned(), issue))?;
+        records.push(record);
+    }
 
-    match max_records_amount {
-        Some(amount) => lines.take(amount).collect(),
-        None => lines.collect(),
+    if records.is_empty() {
+        return Err(ServerError::NoRecords(path.to_owned()));
     }
+    let reordered = records
+        .windows(2)
+        .any(|pair| pair[0].timestamp >= pair[1].timestamp);
+    let record_count = records.len();
+    if reordered {
+        // stable, so that the first record of a timestamp is kept
+        records.sort_by_key(|record| record.timestamp);
+        records.dedup_by_key(|record| record.timestamp);
+    }
+    Ok(LoadedData {
+        duplicate_count: record_count - records.len(),
+        records,
+        reordered,
+    })
 }
 
-fn handle_request(
-    logger: Logger<ServerLogs>,
-    req: Request,
-    start_time: Instant,
-    btc_records: &[String],
+/// What the http server thread needs to answer requests.
+struct HttpState {
     push_port: u16,
-    period: u64,
-) {
+    replays: Arc<Vec<Replay>>,
+    subscribers: Arc<Mutex<Vec<Subscriber>>>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl HttpState {
+    /// The replay of `/<symbol>`.
+    fn replay(&self, url: &str) -> Option<&Replay> {
+        let symbol = url.trim_start_matches('/');
+        self.replays.iter().find(|replay| replay.symbol == symbol)
+    }
+
+    /// The replay of `/<symbol>?at=<timestamp>` and its record at that time.
+    fn record_lookup(&self, url: &str) -> Option<(&Replay, Option<&Record>)> {
+        let (path, timestamp) = url.split_once("?at=")?;
+        let replay = self.replay(path)?;
+        Some((replay, replay.record_at(timestamp.parse().ok()?)))
+    }
+}
+
+fn server_status(state: &HttpState) -> ServerStatus {
+    let replays = &state.replays;
+    let subscribers = state
+        .subscribers
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|subscriber| {
+            Some(SubscriberStatus {
+                address: subscriber.stream.peer_addr().ok()?.to_string(),
+                symbols: subscriber
+                    .instruments
+                    .iter()
+                    .map(|&i| replays[i].symbol.clone())
+                    .collect(),
+            })
+        })
+        .collect();
+    ServerStatus {
+        uptime_secs: replays[0].elapsed().as_secs_f64(),
+        replay_mode: replays[0].mode,
+        period: replays[0].period,
+        replays: replays
+            .iter()
+            .map(|replay| ReplayStatus {
+                symbol: replay.symbol.clone(),
+                index: replay.index(),
+                record_count: replay.record_count(),
+            })
+            .collect(),
+        subscribers,
+    }
+}
+
+fn instrument_info(replay: &Replay) -> InstrumentInfo {
+    let (start_time, end_time) = replay.time_range();
+    let to_rfc3339 = |time: DateTime<Utc>| time.to_rfc3339_opts(SecondsFormat::Millis, true);
+    let records = &replay.records;
+    let (low, high) = if records.is_empty() {
+        (None, None)
+    } else {
+        let low = records
+            .iter()
+            .map(|record| record.low)
+            .fold(f32::MAX, f32::min);
+        let high = records
+            .iter()
+            .map(|record| record.high)
+            .fold(f32::MIN, f32::max);
+        (Some(low), Some(high))
+    };
+    InstrumentInfo {
+        symbol: replay.symbol.clone(),
+        record_count: replay.record_count(),
+        start_time: start_time.map(to_rfc3339),
+        end_time: end_time.map(to_rfc3339),
+        low,
+        high,
+        volume: records.iter().map(|record| record.volume).sum(),
+    }
+}
+
+fn json_response<T: Serialize + ?Sized>(value: &T) -> Response<Cursor<Vec<u8>>> {
+    Response::from_data(serde_json::to_vec(value).unwrap())
+        .with_header("Content-Type: application/json".parse::<Header>().unwrap())
+}
+
+fn handle_request(logger: Logger<ServerLogs>, req: Request, state: &HttpState) {
     let response = match (req.method(), req.url()) {
         (&Method::Get, "/") => Response::from_string("Hello!"),
-        (&Method::Get, "/BTCUSD") => {
-            Response::from_string(get_current_data(start_time, period, btc_records))
+        (&Method::Get, "/health") => json_response(&serde_json::json!({ "status": "ok" })),
+        (&Method::Get, "/status") => json_response(&server_status(state)),
+        (&Method::Get, "/instruments") => {
+            let instruments: Vec<_> = state.replays.iter().map(instrument_info).collect();
+            json_response(&instruments)
+        }
+        (&Method::Get, "/metrics") => {
+            let subscriber_count = state.subscribers.lock().unwrap().len();
+            let metrics = state
+                .metrics
+                .to_prometheus(subscriber_count, &state.replays)
+                .into_bytes();
+            Response::from_data(metrics).with_header(
+                "Content-Type: text/plain; version=0.0.4"
+                    .parse::<Header>()
+                    .unwrap(),
+            )
+        }
+        (&Method::Get, url) if state.replay(url).is_some() => {
+            let replay = state.replay(url).unwrap();
+            match replay.current() {
+                Some(record) => {
+                    let mut data = String::new();
+                    replay.encode(record, &mut data);
+                    Response::from_string(data)
+                }
+                None => Response::from_string("Replay finished").with_status_code(404),
+            }
+        }
+        // e.g. `/BTCUSD?at=1546300800000`, the last record at or before a time
+        (&Method::Get, url) if state.record_lookup(url).is_some() => {
+            match state.record_lookup(url).unwrap() {
+                (replay, Some(record)) => {
+                    let mut data = String::new();
+                    replay.encode(record, &mut data);
+                    Response::from_string(data)
+                }
+                (_, None) => Response::from_string("No record at this time").with_status_code(404),
+            }
+        }
+        // e.g. `/subscribe/BTCUSD,ETHUSD` for a connection pushing both
+        (&Method::Post, url)
+            if url.starts_with("/subscribe/")
+                && parse_subscription(&state.replays, &url["/subscribe/".len()..]).is_some() =>
+        {
+            Response::from_string(state.push_port.to_string())
         }
-        (&Method::Post, "/subscribe/BTCUSD") => Response::from_string(push_port.to_string()),
         (method, url) => Response::from_string(format!("Invalid request: {} at {}", method, url))
             .with_status_code(404),
     };
@@ -249,8 +1257,3 @@ fn handle_request(
         logger.warning(ServerLogs::ClientUnreachable(addr));
     }
 }
-
-fn get_current_data(start_time: Instant, period: u64, data: &[String]) -> &str {
-    let i = (start_time.elapsed().as_micros() / u128::from(period)) as usize;
-    &data[i]
-}
@@ -0,0 +1,238 @@
+// Author: Karim Elmougi
+
+use crate::client::Record;
+use chrono::{TimeZone, Utc};
+use csv::StringRecord;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+pub const COLUMNS: [&str; 8] = [
+    "Unix Timestamp",
+    "Date",
+    "Symbol",
+    "Open",
+    "High",
+    "Low",
+    "Close",
+    "Volume",
+];
+
+/// Why a line of market data can't be replayed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    ColumnCount(usize),
+    /// Past the dates chrono supports.
+    TimestampOutOfRange(i64),
+    InvalidNumber {
+        column: &'static str,
+        value: String,
+    },
+    /// `low <= open, close <= high` doesn't hold.
+    InconsistentPrices,
+    NegativeVolume(f64),
+    OutOfOrder {
+        timestamp: i64,
+        previous: i64,
+    },
+    DuplicateTimestamp(i64),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::ColumnCount(count) => {
+                write!(f, "expected {} columns, found {}", COLUMNS.len(), count)
+            }
+            Issue::TimestampOutOfRange(timestamp) => {
+                write!(f, "timestamp {} is out of range", timestamp)
+            }
+            Issue::InvalidNumber { column, value } => write!(f, "invalid {}: {:?}", column, value),
+            Issue::InconsistentPrices => write!(f, "open and close are not between low and high"),
+            Issue::NegativeVolume(volume) => write!(f, "negative volume: {}", volume),
+            Issue::OutOfOrder {
+                timestamp,
+                previous,
+            } => write!(
+                f,
+                "timestamp {} is before the previous one, {}",
+                timestamp, previous
+            ),
+            Issue::DuplicateTimestamp(timestamp) => write!(f, "duplicate timestamp {}", timestamp),
+        }
+    }
+}
+
+impl Error for Issue {}
+
+/// An issue and the line it is on, starting at 1 for the header.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineIssue {
+    pub line: usize,
+    pub issue: Issue,
+}
+
+impl fmt::Display for LineIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.issue)
+    }
+}
+
+/// Checks records one after the other. Timestamps are compared to the ones of
+/// the valid records only, so that a single bad line is reported once.
+pub struct Validator {
+    checks_order: bool,
+    last_timestamp: Option<i64>,
+    timestamps: HashSet<i64>,
+}
+
+impl Default for Validator {
+    fn default() -> Self {
+        Validator {
+            checks_order: true,
+            last_timestamp: None,
+            timestamps: HashSet::new(),
+        }
+    }
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether timestamps must be increasing and unique, on by default. The
+    /// server sorts its records itself.
+    pub fn with_order_check(self, checks_order: bool) -> Self {
+        Self {
+            checks_order,
+            ..self
+        }
+    }
+
+    pub fn check(&mut self, fields: &[&str]) -> Result<Record, Issue> {
+        if fields.len() != COLUMNS.len() {
+            return Err(Issue::ColumnCount(fields.len()));
+        }
+        let record = Record {
+            timestamp: fields[0].trim().parse().map_err(|_| Issue::InvalidNumber {
+                column: COLUMNS[0],
+                value: fields[0].trim().to_owned(),
+            })?,
+            open: parse_field(fields, 3)?,
+            high: parse_field(fields, 4)?,
+            low: parse_field(fields, 5)?,
+            close: parse_field(fields, 6)?,
+            volume: parse_field(fields, 7)?,
+        };
+
+        if Utc
+            .timestamp_millis_opt(record.timestamp)
+            .single()
+            .is_none()
+        {
+            return Err(Issue::TimestampOutOfRange(record.timestamp));
+        }
+        if record.low > record.open.min(record.close) || record.high < record.open.max(record.close)
+        {
+            return Err(Issue::InconsistentPrices);
+        }
+        if record.volume < 0.0 {
+            return Err(Issue::NegativeVolume(record.volume));
+        }
+        if !self.checks_order {
+            return Ok(record);
+        }
+        if self.timestamps.contains(&record.timestamp) {
+            return Err(Issue::DuplicateTimestamp(record.timestamp));
+        }
+        if let Some(previous) = self.last_timestamp {
+            if record.timestamp < previous {
+                return Err(Issue::OutOfOrder {
+                    timestamp: record.timestamp,
+                    previous,
+                });
+            }
+        }
+
+        self.last_timestamp = Some(record.timestamp);
+        self.timestamps.insert(record.timestamp);
+        Ok(record)
+    }
+
+    /// Checks a record read by a `reader`, the issue being located by its line.
+    pub fn check_record(&mut self, record: &StringRecord) -> Result<Record, LineIssue> {
+        let fields: Vec<_> = record.iter().collect();
+        self.check(&fields).map_err(|issue| LineIssue {
+            line: record
+                .position()
+                .map_or(0, |position| position.line() as usize),
+            issue,
+        })
+    }
+}
+
+/// Reads market data the way the server does, with quoted fields and records
+/// of any length, so that a wrong column count is reported like other issues.
+pub fn reader<R: Read>(input: R) -> csv::Reader<R> {
+    csv::ReaderBuilder::new().flexible(true).from_reader(input)
+}
+
+// "NaN" and "inf" parse as floats but aren't prices
+fn parse_field<T>(fields: &[&str], index: usize) -> Result<T, Issue>
+where
+    T: std::str::FromStr + Into<f64> + Copy,
+{
+    let value = fields[index].trim();
+    match value.parse::<T>() {
+        Ok(number) if number.into().is_finite() => Ok(number),
+        _ => Err(Issue::InvalidNumber {
+            column: COLUMNS[index],
+            value: value.to_owned(),
+        }),
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    /// Records, the header excluded.
+    pub record_count: usize,
+    pub valid_count: usize,
+    pub issues: Vec<LineIssue>,
+}
+
+impl Report {
+    pub fn is_valid(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+/// Checks every record of `input`, a CSV file with a header as replayed by the
+/// server. The header and the valid records are copied to `cleaned`, if any.
+pub fn validate<R: Read>(input: R, cleaned: Option<&mut dyn Write>) -> io::Result<Report> {
+    let mut report = Report::default();
+    let mut validator = Validator::new();
+    let mut reader = reader(input);
+    let mut cleaned = cleaned.map(csv::Writer::from_writer);
+
+    if let Some(cleaned) = cleaned.as_mut() {
+        cleaned.write_record(reader.headers()?)?;
+    }
+    for record in reader.records() {
+        let record = record?;
+        report.record_count += 1;
+        if let Err(issue) = validator.check_record(&record) {
+            report.issues.push(issue);
+            continue;
+        }
+        report.valid_count += 1;
+        if let Some(cleaned) = cleaned.as_mut() {
+            cleaned.write_record(&record)?;
+        }
+    }
+    if let Some(cleaned) = cleaned.as_mut() {
+        cleaned.flush()?;
+    }
+    Ok(report)
+}
@@ -0,0 +1,52 @@
+// Author: Karim Elmougi
+
+// In a test binary of its own, as the global logger of the `log` crate can
+// only be installed once per process.
+
+use ift611_project::logger::{Context, LogFormat, LogLevel, LoggerBuilder, MemoryWriter};
+use std::fmt;
+
+struct Count(usize);
+
+impl Context for Count {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "{}", self.0)
+    }
+}
+
+#[test]
+fn log_bridge_test() {
+    let writer = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_min_level(LogLevel::Debug)
+        .with_format(LogFormat::JsonLines)
+        .start();
+    handle
+        .log_bridge()
+        .with_min_level(LogLevel::Info)
+        .install()
+        .unwrap();
+
+    logger.info(Count(1));
+    log::warn!(target: "dependency", "something {} happened", "odd");
+    log::debug!(target: "dependency", "filtered out by the bridge");
+    log::logger().flush();
+    logger.error(Count(2));
+    handle.shutdown();
+
+    let lines: Vec<serde_json::Value> = writer
+        .contents()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 3);
+    // both queues were flushed before the last message, in no particular order
+    let external = lines[..2]
+        .iter()
+        .find(|line| line["level"] == "WARNING")
+        .unwrap();
+    assert_eq!(external["context"], "dependency: something odd happened");
+    assert_eq!(external["fields"]["target"], "dependency");
+    assert!(lines[..2].iter().any(|line| line["context"] == "1"));
+    assert_eq!(lines[2]["context"], "2");
+}
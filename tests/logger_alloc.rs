@@ -0,0 +1,56 @@
+// Author: Karim Elmougi
+
+// In a test binary of its own, as the counting allocator would otherwise slow
+// down every other test, and their allocations would add up to the counts.
+
+use ift611_project::logger::{Context, LoggerBuilder};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt;
+
+struct Count(usize);
+
+impl Context for Count {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "{}", self.0)
+    }
+}
+
+/// Counts the allocations made by each thread.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|allocations| allocations.set(allocations.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn logger_allocation_test() {
+    let (logger, handle) = LoggerBuilder::new(std::io::sink())
+        .with_message_queue_size(1000)
+        .start();
+    logger.info(Count(0));
+    handle.flush();
+
+    let before = ALLOCATIONS.with(|allocations| allocations.get());
+    for i in 0..100 {
+        logger.info(Count(i));
+    }
+    let after = ALLOCATIONS.with(|allocations| allocations.get());
+    assert_eq!(after - before, 0);
+
+    handle.shutdown();
+}
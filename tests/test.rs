@@ -2,22 +2,163 @@
 
 extern crate ift611_project;
 
+use chrono::{SecondsFormat, TimeZone, Utc};
+use ift611_project::bars::{BarSeries, BarSpec, Resampler};
 use ift611_project::client::*;
+use ift611_project::clock::{Clock, SimulatedClock};
+use ift611_project::config::{ConfigError, ServerConfig};
+use ift611_project::datagen::{MarketDataGenerator, PriceModel};
+use ift611_project::logger::{
+    BinaryRecord, Context, Fields, LogFormat, LogLevel, LoggerBuilder, MemoryRing, MemoryWriter,
+    OverflowPolicy, RotatingFileBuilder, Sink, WriteMode, BINARY_PAYLOAD_SIZE, BINARY_RECORD_SIZE,
+};
+use ift611_project::metrics::{Histogram, Snapshot};
 use ift611_project::server::*;
+use ift611_project::validate::{self, Issue, LineIssue};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A synthetic data file for the server to replay, removed once dropped.
+struct DataFile(PathBuf);
+
+impl DataFile {
+    fn new(test_name: &str, record_count: usize) -> Self {
+        let path = std::env::temp_dir().join(format!("{}_{}.csv", test_name, std::process::id()));
+        MarketDataGenerator::new(0)
+            .write_csv(File::create(&path).unwrap(), record_count)
+            .unwrap();
+        DataFile(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    fn records(&self) -> Vec<Record> {
+        fs::read_to_string(&self.0)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| Record::from_csv_str(line).unwrap())
+            .collect()
+    }
+}
+
+impl Drop for DataFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
 
 #[test]
 fn server_test() {
-    ServerBuilder::new("data.csv")
-        .with_http_port(8080)
+    let data = DataFile::new("server_test", 200);
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
         .with_max_records_amount(Some(100))
         .with_period(100_000)
-        .build_and_start();
-    query_test();
-    subscribe_test();
+        .with_log_writer(MemoryWriter::new())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+    query_test(&url);
+    subscribe_test(&url);
+    server.shutdown();
 }
 
-fn query_test() {
-    let result = TradingPair::BTCUSD.get_record("http://127.0.0.1:8080");
+#[test]
+fn bind_address_test() {
+    let data = DataFile::new("bind_address_test", 200);
+    let address = IpAddr::V6(Ipv6Addr::LOCALHOST);
+    // free when the test starts
+    let push_port = TcpListener::bind((address, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let server = ServerBuilder::new(data.path())
+        .with_bind_address(address)
+        .with_http_port(0)
+        .with_push_port(push_port)
+        .with_period(1000)
+        .with_replay_mode(ReplayMode::Loop)
+        .with_log_writer(MemoryWriter::new())
+        .build_and_start()
+        .unwrap();
+    assert_eq!(server.http_addr().ip(), address);
+    assert_eq!(server.push_port(), push_port);
+
+    let url = format!("http://{}", server.http_addr());
+    query_test(&url);
+    subscribe_test(&url);
+    // only the given address is listened on
+    assert!(TcpStream::connect((Ipv4Addr::LOCALHOST, push_port)).is_err());
+    server.shutdown();
+}
+
+#[test]
+fn max_subscribers_test() {
+    let data = DataFile::new("max_subscribers_test", 10);
+    let log = MemoryWriter::new();
+    // the write timeout of each subscriber rounds down to 0
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_period(1)
+        .with_max_subscriber_count(2)
+        .with_log_writer(log.clone())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+
+    let _connections: Vec<_> = (0..3)
+        .map(|_| TradingPair::BTCUSD.subscribe(&url).unwrap())
+        .collect();
+    wait_for_log(&log, "maximum number of connections is insufficient");
+    let status: ServerStatus = reqwest::get(&format!("{}/status", url))
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(status.subscribers.len(), 2);
+    server.shutdown();
+}
+
+#[test]
+fn bind_error_test() {
+    let data = DataFile::new("bind_error_test", 10);
+    let taken = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let taken_addr = taken.local_addr().unwrap();
+    let start = |http_port, push_port| {
+        let log = MemoryWriter::new();
+        let result = ServerBuilder::new(data.path())
+            .with_bind_address(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .with_http_port(http_port)
+            .with_push_port(push_port)
+            .with_log_writer(log.clone())
+            .build_and_start();
+        (result, log)
+    };
+
+    // a port in use is an error, returned once the logs are flushed
+    for &(http_port, push_port) in &[(0, taken_addr.port()), (taken_addr.port(), 0)] {
+        let (result, log) = start(http_port, push_port);
+        match result {
+            Err(ServerError::Bind(addr, _)) => assert_eq!(addr, taken_addr),
+            Err(err) => panic!("unexpected error: {}", err),
+            Ok(_) => panic!("{} was bound twice", taken_addr),
+        }
+        assert!(log.contents().contains("Loading records data..."));
+    }
+}
+
+fn query_test(url: &str) {
+    let result = TradingPair::BTCUSD.get_record(url);
     assert!(
         result.is_ok(),
         format!(
@@ -27,8 +168,8 @@ fn query_test() {
     );
 }
 
-fn subscribe_test() {
-    let result = TradingPair::BTCUSD.subscribe("http://127.0.0.1:8080");
+fn subscribe_test(url: &str) {
+    let result = TradingPair::BTCUSD.subscribe(url);
     assert!(
         result.is_ok(),
         format!("BTCUSD.subscribe() shouldn't return an error: {:?}", result)
@@ -44,3 +185,1511 @@ fn subscribe_test() {
         )
     );
 }
+
+#[derive(Clone)]
+struct Count(usize);
+
+impl Context for Count {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "{}", self.0)
+    }
+}
+
+#[test]
+fn logger_flush_test() {
+    let writer = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_message_queue_size(1)
+        .start();
+
+    for i in 0..100 {
+        logger.info(Count(i));
+    }
+    handle.flush();
+
+    assert_eq!(writer.contents().lines().count(), 100);
+    handle.shutdown();
+}
+
+#[test]
+fn logger_shutdown_test() {
+    let writer = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_message_queue_size(1)
+        .start();
+
+    let threads: Vec<_> = (0..4)
+        .map(|t| {
+            let logger = logger.clone();
+            thread::spawn(move || {
+                for i in 0..250 {
+                    logger.info(Count(t * 250 + i));
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    handle.shutdown();
+
+    let contents = writer.contents();
+    let mut logged: Vec<usize> = contents
+        .lines()
+        .map(|line| line.rsplit(", ").next().unwrap().parse().unwrap())
+        .collect();
+    logged.sort();
+    assert_eq!(logged, (0..1000).collect::<Vec<_>>());
+
+    // logging after a shutdown must not panic
+    logger.info(Count(1000));
+}
+
+#[derive(Clone)]
+struct SlowCount(usize);
+
+impl Context for SlowCount {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        thread::sleep(Duration::from_millis(1));
+        write!(out, "{}", self.0)
+    }
+}
+
+fn log_with_overflow_policy(overflow_policy: OverflowPolicy) -> (String, usize) {
+    let writer = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_message_queue_size(1)
+        .with_overflow_policy(overflow_policy)
+        .start();
+
+    for i in 0..100 {
+        logger.info(SlowCount(i));
+    }
+    let dropped = logger.dropped_messages();
+    handle.shutdown();
+
+    (writer.contents(), dropped)
+}
+
+#[test]
+fn logger_overflow_test() {
+    let (contents, dropped) = log_with_overflow_policy(OverflowPolicy::DropNewest);
+    assert!(dropped > 0);
+    assert_eq!(contents.lines().count() + dropped, 100);
+
+    let (contents, dropped) = log_with_overflow_policy(OverflowPolicy::DropOldest);
+    assert!(dropped > 0);
+    assert_eq!(contents.lines().count() + dropped, 100);
+    assert!(contents.ends_with(", 99\n"));
+
+    let (contents, dropped) = log_with_overflow_policy(OverflowPolicy::DropAndReport);
+    let (reports, messages): (Vec<_>, Vec<_>) =
+        contents.lines().partition(|line| line.contains("dropped"));
+    let reported: usize = reports
+        .iter()
+        .map(|line| {
+            line.split_whitespace()
+                .nth(9)
+                .unwrap()
+                .parse::<usize>()
+                .unwrap()
+        })
+        .sum();
+    assert!(dropped > 0);
+    assert_eq!(reported, dropped);
+    assert_eq!(messages.len() + dropped, 100);
+}
+
+#[test]
+fn logger_level_test() {
+    let writer = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_min_level(LogLevel::Info)
+        .start();
+
+    logger.trace(Count(0));
+    logger.debug(Count(1));
+    logger.info(Count(2));
+    logger.clone().set_min_level(LogLevel::Trace);
+    logger.trace(Count(3));
+    logger.set_min_level(LogLevel::Error);
+    logger.warning(Count(4));
+    logger.error(Count(5));
+    handle.shutdown();
+
+    let levels: Vec<_> = writer
+        .contents()
+        .lines()
+        .map(|line| line.split(", ").nth(1).unwrap().to_owned())
+        .collect();
+    assert_eq!(levels, ["INFO", "TRACE", "ERROR"]);
+}
+
+struct Tricky;
+
+impl Context for Tricky {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        out.write_str("a, \"tricky\"\ncontext")
+    }
+
+    fn write_fields(&self, fields: &mut Fields) -> fmt::Result {
+        fields.add("answer", &42)?;
+        fields.add("quote", &"\"\\")
+    }
+}
+
+fn log_with_format(format: LogFormat) -> String {
+    let writer = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_format(format)
+        .start();
+    logger.warning(Tricky);
+    logger.info(Tricky);
+    handle.shutdown();
+    writer.contents()
+}
+
+#[test]
+fn logger_format_test() {
+    let contents = log_with_format(LogFormat::Csv);
+    let records: Vec<_> = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(contents.as_bytes())
+        .records()
+        .map(Result::unwrap)
+        .collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(&records[0][1], "WARNING");
+    assert_eq!(&records[0][2], "a, \"tricky\"\ncontext");
+
+    let contents = log_with_format(LogFormat::JsonLines);
+    let lines: Vec<serde_json::Value> = contents
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1]["level"], "INFO");
+    assert_eq!(lines[1]["context"], "a, \"tricky\"\ncontext");
+    assert_eq!(lines[1]["fields"]["answer"], "42");
+    assert_eq!(lines[1]["fields"]["quote"], "\"\\");
+}
+
+/// Takes a while to accept anything written to it.
+struct SlowWriter;
+
+impl std::io::Write for SlowWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        thread::sleep(Duration::from_millis(50));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn logger_sinks_test() {
+    let writer = MemoryWriter::new();
+    let ring = MemoryRing::with_capacity(3);
+    let errors = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_min_level(LogLevel::Debug)
+        .with_batch_size(1)
+        .with_sink(Sink::new(ring.clone()).with_format(LogFormat::JsonLines))
+        .with_sink(Sink::new(errors.clone()).with_min_level(LogLevel::Error))
+        .with_sink(Sink::new(SlowWriter).with_queue_size(1))
+        .start();
+
+    for i in 0..10 {
+        logger.debug(Count(i));
+    }
+    logger.error(Count(10));
+    handle.flush();
+
+    // the slow sink misses lines but the others get all of them
+    let dropped = handle.dropped_lines();
+    assert_eq!(&dropped[..3], &[0, 0, 0]);
+    assert!(dropped[3] > 0, "{:?}", dropped);
+    assert_eq!(writer.contents().lines().count(), 11);
+    assert_eq!(errors.contents().lines().count(), 1);
+
+    let lines = ring.lines();
+    assert_eq!(lines.len(), 3);
+    let last: serde_json::Value = serde_json::from_str(&lines[2]).unwrap();
+    assert_eq!(last["level"], "ERROR");
+    assert_eq!(last["context"], "10");
+
+    handle.shutdown();
+}
+
+/// Records the amount of lines of each write, and counts the flushes.
+#[derive(Clone, Default)]
+struct CountingWriter {
+    writes: Arc<Mutex<Vec<usize>>>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl CountingWriter {
+    /// Waits for `count` writes, or a second at most.
+    fn wait_for_writes(&self, count: usize) -> Vec<usize> {
+        for _ in 0..100 {
+            if self.writes.lock().unwrap().len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.writes.lock().unwrap().clone()
+    }
+}
+
+impl std::io::Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let lines = buf.iter().filter(|&&byte| byte == b'\n').count();
+        self.writes.lock().unwrap().push(lines);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+#[test]
+fn logger_batching_test() {
+    // full batches are written at once, without waiting for the latency
+    let writer = CountingWriter::default();
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_batch_size(4)
+        .with_batch_max_latency(Duration::from_secs(5))
+        .start();
+    let start = Instant::now();
+    for i in 0..8 {
+        logger.info(Count(i));
+    }
+    assert_eq!(writer.wait_for_writes(2), vec![4, 4]);
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(writer.flushes.load(Ordering::SeqCst), 0);
+    handle.flush();
+    assert_eq!(writer.flushes.load(Ordering::SeqCst), 1);
+    handle.shutdown();
+
+    // a partial batch is written once its first message waited long enough
+    let writer = CountingWriter::default();
+    let latency = Duration::from_millis(100);
+    let (logger, handle) = LoggerBuilder::new(writer.clone())
+        .with_batch_size(64)
+        .with_batch_max_latency(latency)
+        .start();
+    let start = Instant::now();
+    for i in 0..3 {
+        logger.info(Count(i));
+    }
+    thread::sleep(latency / 2);
+    assert!(writer.writes.lock().unwrap().is_empty());
+    assert_eq!(writer.wait_for_writes(1), vec![3]);
+    assert!(start.elapsed() >= latency);
+    handle.shutdown();
+    assert_eq!(writer.flushes.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn log_rotation_test() {
+    let dir = std::env::temp_dir().join(format!("log_rotation_test_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log.csv").to_str().unwrap().to_owned();
+
+    let rotating_file = RotatingFileBuilder::new(&path)
+        .with_mode(WriteMode::Truncate)
+        .with_max_size(Some(100))
+        .with_kept_files(2)
+        .with_compression(true)
+        .open()
+        .unwrap();
+    let (logger, handle) = LoggerBuilder::new(rotating_file).start();
+    for i in 0..20 {
+        logger.info(Count(i));
+    }
+    handle.shutdown();
+
+    let mut files: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["log.csv", "log.csv.1.gz", "log.csv.2.gz"]);
+
+    // the most recent messages are in the current file, the ones before in `.1.gz`
+    let current = std::fs::read_to_string(&path).unwrap();
+    assert!(current.len() <= 100);
+    assert!(current.trim_end().ends_with(", 19"));
+    let mut rotated = String::new();
+    let compressed = std::fs::File::open(format!("{}.1.gz", path)).unwrap();
+    flate2::read::GzDecoder::new(compressed)
+        .read_to_string(&mut rotated)
+        .unwrap();
+    let first_current = current.lines().next().unwrap();
+    let last_rotated = rotated.lines().last().unwrap();
+    let index = |line: &str| line.rsplit(", ").next().unwrap().parse::<usize>().unwrap();
+    assert_eq!(index(last_rotated) + 1, index(first_current));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+struct Tick(u32);
+
+impl Context for Tick {
+    fn write_context(&self, out: &mut dyn fmt::Write) -> fmt::Result {
+        write!(out, "tick {}", self.0)
+    }
+
+    fn event_id(&self) -> u16 {
+        7
+    }
+
+    fn write_payload(&self, payload: &mut [u8; BINARY_PAYLOAD_SIZE]) {
+        payload[..4].copy_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+#[test]
+fn logger_binary_format_test() {
+    let writer = MemoryWriter::new();
+    let bytes = MemoryWriter::new();
+    let (logger, handle) = LoggerBuilder::new(bytes.clone())
+        .with_format(LogFormat::Binary)
+        .with_sink(Sink::new(writer.clone()).with_format(LogFormat::Csv))
+        .start();
+    for i in 0..10 {
+        logger.warning(Tick(i));
+    }
+    handle.shutdown();
+
+    let text: Vec<_> = writer.contents().lines().map(str::to_owned).collect();
+    let bytes = bytes.bytes();
+    assert_eq!(bytes.len(), 10 * BINARY_RECORD_SIZE);
+
+    let mut reader = &bytes[..];
+    for (i, line) in text.iter().enumerate() {
+        let record = BinaryRecord::read_from(&mut reader).unwrap().unwrap();
+        assert_eq!(record.level, LogLevel::Warning);
+        assert_eq!(record.event_id, 7);
+        assert_eq!(&record.payload[..4], &(i as u32).to_le_bytes());
+        assert!(record.payload[4..].iter().all(|&byte| byte == 0));
+        // the binary timestamp keeps the nanoseconds of the text formats
+        let timestamp = line.split(',').next().unwrap();
+        assert_eq!(
+            record
+                .timestamp
+                .to_rfc3339_opts(chrono::SecondsFormat::Nanos, true),
+            timestamp
+        );
+    }
+    assert!(BinaryRecord::read_from(&mut reader).unwrap().is_none());
+}
+
+/// Decodes a log rotated to `path.N` ... `path.1` and `path`, oldest first.
+fn read_rotated_binary_log(path: &str) -> (usize, Vec<BinaryRecord>) {
+    let mut paths: Vec<_> = (1..)
+        .map(|index| format!("{}.{}", path, index))
+        .take_while(|path| std::path::Path::new(path).exists())
+        .collect();
+    paths.reverse();
+    paths.push(path.to_owned());
+
+    let mut records = Vec::new();
+    for path in &paths {
+        let bytes = fs::read(path).unwrap();
+        assert_eq!(bytes.len() % BINARY_RECORD_SIZE, 0, "{}", path);
+        let mut reader = &bytes[..];
+        while let Some(record) = BinaryRecord::read_from(&mut reader).unwrap() {
+            records.push(record);
+        }
+    }
+    (paths.len(), records)
+}
+
+#[test]
+fn binary_log_rotation_test() {
+    let dir = std::env::temp_dir().join(format!("binary_log_rotation_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("log.bin").to_str().unwrap().to_owned();
+
+    let rotating_file = RotatingFileBuilder::new(&path)
+        .with_mode(WriteMode::Truncate)
+        .with_max_size(Some(200))
+        .with_kept_files(10)
+        .with_record_size(Some(BINARY_RECORD_SIZE))
+        .open()
+        .unwrap();
+    let (logger, handle) = LoggerBuilder::new(rotating_file)
+        .with_format(LogFormat::Binary)
+        .start();
+    // the payload of `Tick(10)` starts with a newline byte
+    for i in 0..20 {
+        logger.info(Tick(i));
+    }
+    handle.shutdown();
+
+    let (file_count, records) = read_rotated_binary_log(&path);
+    assert_eq!(file_count, 4);
+    let ticks: Vec<_> = records
+        .iter()
+        .map(|record| {
+            u32::from_le_bytes([
+                record.payload[0],
+                record.payload[1],
+                record.payload[2],
+                record.payload[3],
+            ])
+        })
+        .collect();
+    assert_eq!(ticks, (0..20).collect::<Vec<_>>());
+
+    // the server cuts its binary log between records by itself
+    let data = DataFile::new("binary_log_rotation_test", 10);
+    let server_log = dir.join("server.bin").to_str().unwrap().to_owned();
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_log_format(LogFormat::Binary)
+        .with_log_rotating_file(
+            RotatingFileBuilder::new(&server_log)
+                .with_max_size(Some(50))
+                .with_kept_files(10),
+        )
+        .build_and_start()
+        .unwrap();
+    server.shutdown();
+    let (file_count, records) = read_rotated_binary_log(&server_log);
+    assert_eq!(file_count, records.len());
+    assert!(records.len() > 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn logdump(path: &str, source: &str) -> Vec<serde_json::Value> {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_logdump"))
+        .args([path, "--format", "json", "--source", source])
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[test]
+fn logdump_test() {
+    let dir = std::env::temp_dir().join(format!("logdump_test_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let client_log = dir.join("client.bin").to_str().unwrap().to_owned();
+    let server_log = dir.join("server.bin").to_str().unwrap().to_owned();
+
+    let (logger, handle) = LoggerBuilder::new(File::create(&client_log).unwrap())
+        .with_format(LogFormat::Binary)
+        .start();
+    logger.info(DecisionLogs::Buy);
+    logger.info(DecisionLogs::Latency(
+        LatencyStage::Parsing,
+        Percentiles {
+            p50: 1200,
+            p99: 4500,
+            p999: 9000,
+        },
+    ));
+    handle.shutdown();
+
+    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 4242);
+    let (logger, handle) = LoggerBuilder::new(File::create(&server_log).unwrap())
+        .with_format(LogFormat::Binary)
+        .start();
+    logger.warning(ServerLogs::ClientUnreachable(addr));
+    handle.shutdown();
+
+    let mut expected = String::new();
+    DecisionLogs::Latency(
+        LatencyStage::Parsing,
+        Percentiles {
+            p50: 1200,
+            p99: 4500,
+            p999: 9000,
+        },
+    )
+    .write_context(&mut expected)
+    .unwrap();
+    let records = logdump(&client_log, "client");
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["message"], "Buy");
+    assert_eq!(records[0]["payload"], "");
+    assert_eq!(records[1]["event_id"], 4);
+    assert_eq!(records[1]["message"], expected.as_str());
+
+    let records = logdump(&server_log, "server");
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0]["level"], "WARNING");
+    let mut expected = String::new();
+    ServerLogs::ClientUnreachable(addr)
+        .write_context(&mut expected)
+        .unwrap();
+    assert_eq!(records[0]["message"], expected.as_str());
+    assert!(expected.contains("10.0.0.1:4242"));
+
+    // the client has no event 7, so its payload is dumped in hexadecimal
+    let records = logdump(&server_log, "client");
+    assert_eq!(records[0]["event_id"], 7);
+    assert_eq!(records[0]["message"], "");
+    assert_eq!(
+        records[0]["payload"],
+        "00000000000000000000ffff0a00000192100000"
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn wait_for_log(writer: &MemoryWriter, message: &str) {
+    for _ in 0..500 {
+        if writer.contents().contains(message) {
+            return;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("`{}` was never logged", message);
+}
+
+#[test]
+fn simulated_clock_test() {
+    let data = DataFile::new("simulated_clock_test", 200);
+    let start_time = Utc.ymd(2019, 1, 1).and_hms(12, 0, 0);
+    let clock = Arc::new(SimulatedClock::new(start_time));
+    let period = Duration::from_millis(1);
+    let log = MemoryWriter::new();
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_max_records_amount(Some(10))
+        .with_period(period.as_micros() as u64)
+        .with_log_writer(log.clone())
+        .with_log_format(LogFormat::Csv)
+        .with_clock(clock.clone())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+
+    let records: Vec<String> = data.records()[..10]
+        .iter()
+        .map(Record::to_csv_str)
+        .collect();
+    let get_record = || TradingPair::BTCUSD.get_record(&url).unwrap().to_csv_str();
+
+    // time stands still until the clock is advanced
+    thread::sleep(Duration::from_millis(20));
+    assert_eq!(get_record(), records[0]);
+    clock.advance(period * 3);
+    assert_eq!(get_record(), records[3]);
+
+    let mut connection = TradingPair::BTCUSD.subscribe(&url).unwrap();
+    wait_for_log(&log, "adding subscriber");
+    for record in &records[4..7] {
+        clock.advance(period);
+        assert_eq!(&connection.next().unwrap().to_csv_str(), record);
+    }
+
+    // every message is timestamped by the simulated clock
+    let first_line = log.contents().lines().next().unwrap().to_owned();
+    assert!(first_line.starts_with("2019-01-01T12:00:00.000000000Z,INFO,"));
+    server.shutdown();
+}
+
+#[test]
+fn simulated_clock_late_ticks_test() {
+    let clock = SimulatedClock::new(Utc.ymd(2019, 1, 1).and_hms(12, 0, 0));
+    let ticker = clock.ticker(Duration::from_nanos(1));
+    let slow_ticker = clock.ticker(Duration::from_secs(u64::MAX / 2));
+
+    // more than `u32::MAX` ticks are missed at once
+    clock.advance(Duration::from_secs(10));
+    assert!(ticker.try_recv().is_ok());
+    clock.advance(Duration::from_nanos(0));
+    assert!(ticker.try_recv().is_err());
+    clock.advance(Duration::from_nanos(1));
+    assert!(ticker.try_recv().is_ok());
+
+    // past the largest duration, time stops and the slow ticker never fires
+    clock.advance(Duration::MAX);
+    clock.advance(Duration::MAX);
+    assert_eq!(clock.elapsed(), Duration::MAX);
+    assert!(slow_ticker.try_recv().is_err());
+}
+
+#[test]
+fn latency_test() {
+    let data = DataFile::new("latency_test", 200);
+    let start_time = Utc.ymd(2019, 1, 1).and_hms(12, 0, 0);
+    let clock = Arc::new(SimulatedClock::new(start_time));
+    let period = Duration::from_millis(1);
+    let log = MemoryWriter::new();
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_max_records_amount(Some(10))
+        .with_period(period.as_micros() as u64)
+        .with_log_writer(log.clone())
+        .with_clock(clock.clone())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+
+    let mut connection = TradingPair::BTCUSD.subscribe(&url).unwrap();
+    wait_for_log(&log, "adding subscriber");
+    let stats = LatencyStats::new();
+    for i in 1..=3 {
+        clock.advance(period);
+        let timed = connection.next_timed().unwrap();
+        // pushed records carry the time the server sent them
+        assert_eq!(
+            timed.sent_at,
+            Some(start_time + chrono::Duration::milliseconds(i))
+        );
+        assert!(timed.received_at <= timed.parsed_at);
+        stats.record(&timed, timed.parsed_at);
+    }
+    server.shutdown();
+
+    // the simulated send times lie in the past, the network stage is positive
+    assert_eq!(stats.count(LatencyStage::Network), 3);
+    assert_eq!(stats.count(LatencyStage::TickToTrade), 3);
+    let decision = stats.percentiles(LatencyStage::Decision).unwrap();
+    assert_eq!(
+        decision,
+        Percentiles {
+            p50: 0,
+            p99: 0,
+            p999: 0
+        }
+    );
+    assert!(stats.percentiles(LatencyStage::Network).unwrap().p50 > 0);
+
+    // without a send time, only the client's stages are measured
+    let record = Record::default();
+    assert_eq!(send_timestamp(&record.to_csv_str()), None);
+    assert_eq!(
+        send_timestamp("0,0,BTCUSD,1,2,0.5,1.5,10,1546344000000000001\n"),
+        Some(Utc.timestamp(1_546_344_000, 1))
+    );
+}
+
+#[test]
+fn histogram_test() {
+    let histogram = Histogram::new();
+    assert_eq!(histogram.percentile(50.0), None);
+    assert_eq!(histogram.snapshot(), Snapshot::default());
+
+    // small values are exact
+    for nanos in 1..=10 {
+        histogram.record(nanos);
+    }
+    assert_eq!(histogram.percentile(50.0), Some(5));
+    assert_eq!(histogram.percentile(100.0), Some(10));
+
+    // larger ones are within the bucket's precision
+    histogram.reset();
+    histogram.record_duration(Duration::from_millis(1));
+    let p50 = histogram.percentile(50.0).unwrap();
+    assert!((1_000_000..=1_000_000 + 1_000_000 / 32).contains(&p50));
+    histogram.record(u64::MAX);
+    assert_eq!(histogram.percentile(100.0), Some(u64::MAX));
+
+    // threads record to the same histogram, or to their own to be merged
+    let shared = Arc::new(Histogram::new());
+    let merged = Histogram::new();
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let own = Histogram::new();
+                for nanos in 0..1000 {
+                    shared.record(nanos);
+                    own.record(nanos);
+                }
+                own
+            })
+        })
+        .collect();
+    for thread in threads {
+        merged.merge(&thread.join().unwrap());
+    }
+    let snapshot = shared.snapshot();
+    assert_eq!(snapshot, merged.snapshot());
+    assert_eq!(snapshot.count, 4000);
+    assert_eq!((snapshot.min, snapshot.max, snapshot.mean), (0, 999, 499));
+    assert!((990..=999).contains(&snapshot.p99));
+
+    // snapshots are compared across runs as JSON
+    let json = snapshot.to_json();
+    assert!(json.starts_with(r#"{"count":4000,"min":0,"max":999,"#));
+    assert_eq!(serde_json::from_str::<Snapshot>(&json).unwrap(), snapshot);
+}
+
+fn wait_for_metric(url: &str, line: &str) -> String {
+    for _ in 0..100 {
+        let metrics = reqwest::get(&format!("{}/metrics", url))
+            .unwrap()
+            .text()
+            .unwrap();
+        if metrics.lines().any(|metric| metric == line) {
+            return metrics;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    panic!("`{}` was never reported", line);
+}
+
+#[test]
+fn metrics_endpoint_test() {
+    let data = DataFile::new("metrics_endpoint_test", 200);
+    let clock = Arc::new(SimulatedClock::new(Utc.ymd(2019, 1, 1).and_hms(12, 0, 0)));
+    let period = Duration::from_millis(1);
+    let log = MemoryWriter::new();
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_max_records_amount(Some(100))
+        .with_period(period.as_micros() as u64)
+        .with_log_writer(log.clone())
+        .with_clock(clock.clone())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+
+    let mut connection = TradingPair::BTCUSD.subscribe(&url).unwrap();
+    wait_for_log(&log, "adding subscriber");
+    for _ in 0..3 {
+        clock.advance(period);
+        assert!(connection.next().is_some());
+    }
+
+    let metrics = wait_for_metric(&url, "hft_push_latency_seconds_count 3");
+    for line in &[
+        "# TYPE hft_records_pushed_total counter",
+        "hft_subscribers 1",
+        "hft_records_pushed_total 3",
+        "hft_dropped_subscribers_total 0",
+        "hft_missed_deadlines_total 0",
+        "hft_replay_index{symbol=\"BTCUSD\"} 3",
+        "# TYPE hft_push_latency_seconds summary",
+    ] {
+        assert!(metrics.lines().any(|metric| metric == *line), "{}", line);
+    }
+    assert_eq!(server.metrics().records_pushed(), 3);
+    assert!(server.metrics().bytes_written() > 0);
+
+    // a subscriber that went away is dropped once a push to it fails
+    drop(connection);
+    for _ in 0..50 {
+        if server.metrics().dropped_subscribers() > 0 {
+            break;
+        }
+        clock.advance(period);
+        thread::sleep(Duration::from_millis(10));
+    }
+    wait_for_metric(&url, "hft_subscribers 0");
+    assert_eq!(server.metrics().dropped_subscribers(), 1);
+    server.shutdown();
+}
+
+#[test]
+fn status_endpoints_test() {
+    let data = DataFile::new("status_endpoints_test", 200);
+    let clock = Arc::new(SimulatedClock::new(Utc.ymd(2019, 1, 1).and_hms(12, 0, 0)));
+    let period = Duration::from_millis(1);
+    let log = MemoryWriter::new();
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_max_records_amount(Some(10))
+        .with_period(period.as_micros() as u64)
+        .with_log_writer(log.clone())
+        .with_clock(clock.clone())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+    let get = |path: &str| reqwest::get(&format!("{}{}", url, path)).unwrap();
+
+    let mut response = get("/health");
+    assert!(response.status().is_success());
+    assert_eq!(
+        response.headers()["Content-Type"].to_str().unwrap(),
+        "application/json"
+    );
+    assert_eq!(response.text().unwrap(), r#"{"status":"ok"}"#);
+
+    let _connection = TradingPair::BTCUSD.subscribe(&url).unwrap();
+    wait_for_log(&log, "adding subscriber");
+    clock.advance(period * 3);
+    let status: ServerStatus = get("/status").json().unwrap();
+    assert_eq!(status.uptime_secs, 0.003);
+    assert_eq!(status.replay_mode, ReplayMode::Once);
+    assert_eq!(status.period, 1000);
+    assert_eq!(
+        status.replays,
+        vec![ReplayStatus {
+            symbol: "BTCUSD".to_owned(),
+            index: Some(3),
+            record_count: 10,
+        }]
+    );
+    assert_eq!(status.subscribers.len(), 1);
+    assert_eq!(status.subscribers[0].symbols, vec!["BTCUSD".to_owned()]);
+
+    clock.advance(period * 10);
+    let status: ServerStatus = get("/status").json().unwrap();
+    assert_eq!(status.replays[0].index, None);
+
+    let records = &data.records()[..10];
+    let mut instruments: Vec<InstrumentInfo> = get("/instruments").json().unwrap();
+    // JSON doesn't round trip every float exactly
+    let volume: f64 = records.iter().map(|record| record.volume).sum();
+    assert!((instruments[0].volume - volume).abs() < 1e-9);
+    instruments[0].volume = volume;
+    assert_eq!(
+        instruments,
+        vec![InstrumentInfo {
+            symbol: "BTCUSD".to_owned(),
+            record_count: 10,
+            start_time: Some(
+                Utc.timestamp_millis(records[0].timestamp)
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+            ),
+            end_time: Some(
+                Utc.timestamp_millis(records[9].timestamp)
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+            ),
+            low: records
+                .iter()
+                .map(|record| record.low)
+                .fold(None, |low, value| { Some(value.min(low.unwrap_or(value))) }),
+            high: records
+                .iter()
+                .map(|record| record.high)
+                .fold(None, |high, value| {
+                    Some(value.max(high.unwrap_or(value)))
+                }),
+            volume,
+        }]
+    );
+
+    // records can be looked up by time, even once the replay finished
+    let record_at = |timestamp: i64| get(&format!("/BTCUSD?at={}", timestamp));
+    let mut response = record_at(records[4].timestamp + 1);
+    assert!(response.status().is_success());
+    let record = Record::from_csv_str(&response.text().unwrap()).unwrap();
+    assert_eq!(record.to_csv_str(), records[4].to_csv_str());
+    assert_eq!(record_at(records[0].timestamp - 1).status(), 404);
+    assert_eq!(get("/BTCUSD?at=soon").status(), 404);
+    assert_eq!(get("/LTCUSD?at=0").status(), 404);
+    server.shutdown();
+}
+
+#[test]
+fn multi_pair_test() {
+    let data = DataFile::new("multi_pair_test", 200);
+    let clock = Arc::new(SimulatedClock::new(Utc.ymd(2019, 1, 1).and_hms(12, 0, 0)));
+    let period = Duration::from_millis(1);
+    let log = MemoryWriter::new();
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_max_records_amount(Some(10))
+        .with_instrument("ETHUSD", data.path(), Some(3))
+        .with_period(period.as_micros() as u64)
+        .with_log_writer(log.clone())
+        .with_clock(clock.clone())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+
+    // the server has no LTCUSD instrument
+    assert!(TradingPair::LTCUSD.subscribe(&url).is_err());
+    assert!(
+        TradingPair::subscribe_many(&url, &[TradingPair::BTCUSD, TradingPair::LTCUSD]).is_err()
+    );
+
+    let mut both =
+        TradingPair::subscribe_many(&url, &[TradingPair::ETHUSD, TradingPair::BTCUSD]).unwrap();
+    let mut eth = TradingPair::ETHUSD.subscribe(&url).unwrap();
+    for _ in 0..500 {
+        if log.contents().matches("adding subscriber").count() == 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let mut queues = PairQueues::with_capacity(10);
+    for _ in 0..2 {
+        clock.advance(period);
+        let first = both.next_timed().unwrap();
+        let second = both.next_timed().unwrap();
+        // records are tagged with their pair, in the order subscribed to
+        assert_eq!(
+            (first.pair, second.pair),
+            (TradingPair::ETHUSD, TradingPair::BTCUSD)
+        );
+        assert_eq!(first.sent_at, second.sent_at);
+        queues.push(first.pair, first.record);
+        queues.push(second.pair, second.record);
+        assert_eq!(eth.next_timed().unwrap().pair, TradingPair::ETHUSD);
+    }
+    assert_eq!(queues.get(TradingPair::ETHUSD).unwrap().len(), 2);
+    assert_eq!(queues.get(TradingPair::BTCUSD).unwrap().len(), 2);
+    assert!(queues.get(TradingPair::LTCUSD).is_none());
+
+    // past the last ETHUSD record, only BTCUSD records are pushed
+    clock.advance(period);
+    assert_eq!(both.next_timed().unwrap().pair, TradingPair::BTCUSD);
+    clock.advance(period);
+    assert_eq!(both.next_timed().unwrap().pair, TradingPair::BTCUSD);
+
+    // a subscriber must first send the symbols it subscribes to
+    let mut stream = TcpStream::connect(("127.0.0.1", server.push_port())).unwrap();
+    writeln!(stream, "LTCUSD").unwrap();
+    wait_for_log(&log, "invalid subscription");
+
+    // a client that never sends its symbols holds up neither the other
+    // subscribers nor the shutdown
+    let mut silent = TcpStream::connect(("127.0.0.1", server.push_port())).unwrap();
+    thread::sleep(Duration::from_millis(50));
+    let start = Instant::now();
+    let _btc = TradingPair::BTCUSD.subscribe(&url).unwrap();
+    while log.contents().matches("adding subscriber").count() < 3 {
+        thread::sleep(Duration::from_millis(10));
+    }
+    server.shutdown();
+    assert!(start.elapsed() < Duration::from_millis(500));
+    // its handshake was stopped by the shutdown rather than left running
+    silent
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    assert_eq!(silent.read(&mut [0; 16]).unwrap(), 0);
+}
+
+fn minute_record(minute: i64, close: f32, volume: f64) -> Record {
+    Record {
+        timestamp: 1_546_300_800_000 + minute * 60_000,
+        open: close - 1.0,
+        high: close + 2.0,
+        low: close - 2.0,
+        close,
+        volume,
+    }
+}
+
+#[test]
+fn resampler_test() {
+    assert_eq!("5m".parse(), Ok(BarSpec::Time(Duration::from_secs(300))));
+    assert_eq!("1h".parse(), Ok(BarSpec::Time(Duration::from_secs(3600))));
+    assert_eq!("100t".parse(), Ok(BarSpec::Ticks(100)));
+    assert_eq!("2.5v".parse(), Ok(BarSpec::Volume(2.5)));
+    for invalid in &[
+        "",
+        "m",
+        "0m",
+        "5d",
+        "0t",
+        "-1v",
+        "é",
+        "99999999999999999h",
+        "18446744073709551615s",
+    ] {
+        assert!(invalid.parse::<BarSpec>().is_err(), "{}", invalid);
+    }
+
+    // a 5 minute bar is completed by the first record of the next one
+    let mut resampler = Resampler::new("5m".parse().unwrap());
+    for minute in 0..5 {
+        assert!(resampler
+            .push(&minute_record(minute, 100.0 + minute as f32, 1.0))
+            .is_none());
+    }
+    let bar = resampler.push(&minute_record(5, 110.0, 1.0)).unwrap();
+    assert_eq!(bar.timestamp, 1_546_300_800_000);
+    assert_eq!(
+        (bar.open, bar.high, bar.low, bar.close),
+        (99.0, 106.0, 98.0, 104.0)
+    );
+    assert_eq!(bar.volume, 5.0);
+    // periods without records have no bars
+    let bar = resampler.push(&minute_record(17, 120.0, 1.0)).unwrap();
+    assert_eq!(bar.timestamp, 1_546_300_800_000 + 5 * 60_000);
+    assert_eq!(
+        resampler.partial().unwrap().timestamp,
+        1_546_300_800_000 + 15 * 60_000
+    );
+
+    let mut resampler = Resampler::new(BarSpec::Ticks(3));
+    let bars: Vec<_> = (0..7)
+        .filter_map(|minute| resampler.push(&minute_record(minute, 100.0, 1.0)))
+        .collect();
+    assert_eq!(bars.len(), 2);
+    assert_eq!(bars[1].timestamp, 1_546_300_800_000 + 3 * 60_000);
+    assert_eq!(bars[1].volume, 3.0);
+
+    let mut resampler = Resampler::new(BarSpec::Volume(2.5));
+    let volumes: Vec<_> = (0..6)
+        .filter_map(|minute| resampler.push(&minute_record(minute, 100.0, 1.0)))
+        .map(|bar| bar.volume)
+        .collect();
+    assert_eq!(volumes, vec![3.0, 3.0]);
+
+    // strategies decide on bars the same way as on records
+    let mut series = BarSeries::new(BarSpec::Ticks(2), 10);
+    let mut decisions = Vec::new();
+    for (minute, &close) in [100.0, 100.0, 110.0, 110.0, 90.0, 90.0].iter().enumerate() {
+        if let Some(bars) = series.push(&minute_record(minute as i64, close, 1.0)) {
+            decisions.push(TradingStrategy::Dummy.make_decision(bars));
+        }
+    }
+    assert_eq!(series.bars().len(), 3);
+    assert_eq!(format!("{:?}", decisions), "[Wait, Buy, Sell]");
+}
+
+fn generate_csv(model: PriceModel, seed: u64) -> String {
+    let mut csv = Vec::new();
+    MarketDataGenerator::new(seed)
+        .with_model(model)
+        .write_csv(&mut csv, 200)
+        .unwrap();
+    String::from_utf8(csv).unwrap()
+}
+
+#[test]
+fn datagen_test() {
+    let gbm = generate_csv(PriceModel::GeometricBrownian, 0);
+    assert_eq!(gbm, generate_csv(PriceModel::GeometricBrownian, 0));
+    assert_ne!(gbm, generate_csv(PriceModel::GeometricBrownian, 1));
+
+    let ou = generate_csv(
+        PriceModel::MeanReverting {
+            speed: 1e5,
+            mean: 3000.0,
+        },
+        42,
+    );
+    let jump = generate_csv(
+        PriceModel::JumpDiffusion {
+            intensity: 1e4,
+            jump_mean: 0.0,
+            jump_volatility: 0.05,
+        },
+        42,
+    );
+    for csv in &[&gbm, &ou, &jump] {
+        let mut lines = csv.lines();
+        assert_eq!(
+            lines.next(),
+            Some("Unix Timestamp,Date,Symbol,Open,High,Low,Close,Volume")
+        );
+        let records: Vec<_> = lines
+            .map(|line| Record::from_csv_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 200);
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record.timestamp, 1_546_300_800_000 + i as i64 * 60_000);
+            assert!(record.low <= record.open.min(record.close));
+            assert!(record.high >= record.open.max(record.close));
+            assert!(record.volume > 0.0);
+        }
+        // every record opens at the previous close
+        for pair in records.windows(2) {
+            assert_eq!(pair[1].open, pair[0].close);
+        }
+    }
+    assert_ne!(gbm, jump);
+    // pulled from the start price to the mean within a few records
+    for line in ou.lines().skip(21) {
+        let record = Record::from_csv_str(line).unwrap();
+        assert!((2970.0..=3030.0).contains(&record.close), "{:?}", record);
+    }
+
+    // small prices aren't rounded to 0
+    let mut csv = Vec::new();
+    MarketDataGenerator::new(0)
+        .with_start_price(0.001)
+        .write_csv(&mut csv, 100)
+        .unwrap();
+    assert!(validate::validate(csv.as_slice(), None).unwrap().is_valid());
+
+    // the records end rather than panic past the dates chrono supports
+    let mut generator = MarketDataGenerator::new(0).with_interval(Duration::from_secs(u64::MAX));
+    assert!(generator.next_record().is_some());
+    assert!(generator.next_record().is_none());
+    assert!(generator.write_csv(Vec::new(), 1).is_err());
+
+    let data = DataFile::new("datagen_test", 200);
+    assert_eq!(fs::read_to_string(data.path()).unwrap(), gbm);
+    let server = ServerBuilder::new(data.path())
+        .with_http_port(0)
+        .with_period(100_000)
+        .with_log_writer(MemoryWriter::new())
+        .build_and_start()
+        .unwrap();
+    let url = format!("http://{}", server.http_addr());
+    assert!(TradingPair::BTCUSD.get_record(&url).is_ok());
+    server.shutdown();
+}
+
+#[test]
+fn datagen_args_test() {
+    let datagen = |args: &[&str]| {
+        std::process::Command::new(env!("CARGO_BIN_EXE_datagen"))
+            .args(["-", "--count", "10"])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = datagen(&["--model", "ou", "--mean", "20"]);
+    assert!(output.status.success());
+    assert!(validate::validate(&output.stdout[..], None)
+        .unwrap()
+        .is_valid());
+
+    // data `validate` would reject isn't generated
+    for args in &[
+        &["--model", "ou", "--mean", "0"][..],
+        &["--model", "ou", "--mean=-5"],
+        &["--model", "ou", "--drift", "0.1"],
+        &["--volatility=-0.1"],
+        &["--volume=-1"],
+        &["--volume", "NaN"],
+    ] {
+        let output = datagen(args);
+        assert!(!output.status.success(), "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+    }
+}
+
+#[test]
+fn validate_test() {
+    let input = "Unix Timestamp,Date,Symbol,Open,High,Low,Close,Volume
+1546300800000,2019-01-01 00:00:00,BTCUSD,3600.00,3603.60,3599.00,3602.60,53.3594
+1546300860000,2019-01-01 00:01:00,BTCUSD,3602.60,3605.55,3601.60
+1546300920000,2019-01-01 00:02:00,BTCUSD,3604.55,abc,3601.60,3604.55,11.0774
+1546300980000,2019-01-01 00:03:00,BTCUSD,3604.55,3603.00,3601.60,3604.55,11.0774
+1546301040000,2019-01-01 00:04:00,BTCUSD,3604.55,3605.55,3601.60,3604.55,-1.0
+1546301100000,2019-01-01 00:05:00,BTCUSD,3604.55,3605.55,3601.60,3604.55,NaN
+1546301160000,2019-01-01 00:06:00,BTCUSD,3604.55,3605.55,3601.60,3604.55,11.0774
+1546300800000,2019-01-01 00:00:00,BTCUSD,3600.00,3603.60,3599.00,3602.60,53.3594
+1546300860000,2019-01-01 00:01:00,BTCUSD,3602.60,3605.55,3601.60,3604.55,11.0774
+1546301220000,2019-01-01 00:07:00,BTCUSD,3604.55,3605.55,3601.60,3604.55,0
+";
+    let mut cleaned = Vec::new();
+    let report = validate::validate(input.as_bytes(), Some(&mut cleaned)).unwrap();
+    assert_eq!(report.record_count, 10);
+    assert_eq!(report.valid_count, 3);
+    assert!(!report.is_valid());
+
+    let issues: Vec<_> = report
+        .issues
+        .iter()
+        .map(|LineIssue { line, issue }| (*line, issue.clone()))
+        .collect();
+    assert_eq!(
+        issues,
+        vec![
+            (3, Issue::ColumnCount(6)),
+            (
+                4,
+                Issue::InvalidNumber {
+                    column: "High",
+                    value: "abc".to_owned()
+                }
+            ),
+            (5, Issue::InconsistentPrices),
+            (6, Issue::NegativeVolume(-1.0)),
+            (
+                7,
+                Issue::InvalidNumber {
+                    column: "Volume",
+                    value: "NaN".to_owned()
+                }
+            ),
+            (9, Issue::DuplicateTimestamp(1_546_300_800_000)),
+            (
+                10,
+                Issue::OutOfOrder {
+                    timestamp: 1_546_300_860_000,
+                    previous: 1_546_301_160_000
+                }
+            ),
+        ]
+    );
+    assert_eq!(
+        report.issues[0].to_string(),
+        "line 3: expected 8 columns, found 6"
+    );
+
+    // the cleaned file only has valid records
+    let cleaned = String::from_utf8(cleaned).unwrap();
+    let lines: Vec<_> = input.lines().collect();
+    assert_eq!(
+        cleaned.lines().collect::<Vec<_>>(),
+        vec![lines[0], lines[1], lines[7], lines[10]]
+    );
+    let report = validate::validate(cleaned.as_bytes(), None).unwrap();
+    assert!(report.is_valid());
+    assert_eq!(report.valid_count, 3);
+
+    // fields are read like the server reads them, quotes included
+    let quoted = format!(
+        "{}\n1546300800000,\"2019-01-01 00:00:00\",\"BTCUSD\",3600.00,3603.60,3599.00,3602.60,\"53.3594\"\n",
+        lines[0]
+    );
+    let report = validate::validate(quoted.as_bytes(), None).unwrap();
+    assert!(report.is_valid());
+    assert_eq!(report.valid_count, 1);
+    let path = std::env::temp_dir().join(format!("validate_test_{}.csv", std::process::id()));
+    fs::write(&path, &quoted).unwrap();
+    let records = load_data(path.to_str().unwrap(), None).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(records.records[0].volume, 53.3594);
+
+    // past the dates chrono supports, the record couldn't be served
+    let out_of_range = format!(
+        "{}\n99999999999999999,2019-01-01 00:00:00,BTCUSD,3600,3603.6,3599,3602.6,53.3594\n",
+        lines[0]
+    );
+    let report = validate::validate(out_of_range.as_bytes(), None).unwrap();
+    assert_eq!(
+        report.issues,
+        vec![LineIssue {
+            line: 2,
+            issue: Issue::TimestampOutOfRange(99_999_999_999_999_999)
+        }]
+    );
+}
+
+#[test]
+fn load_data_test() {
+    let path = std::env::temp_dir().join(format!("load_data_test_{}.csv", std::process::id()));
+    let path = path.to_str().unwrap();
+    let header = "Unix Timestamp,Date,Symbol,Open,High,Low,Close,Volume\n";
+    let records = "1546300800000,2019-01-01 00:00:00,BTCUSD,3600.00,3603.60,3599.00,3602.60,53.3594
+1546300860000,2019-01-01 00:01:00,BTCUSD,3602.60,3605.55,3601.60,3604.55,11.0774
+1546300980000,2019-01-01 00:03:00,BTCUSD,3604.55,3606.00,3603.00,3605.00,20.5
+";
+
+    std::fs::write(path, format!("{}{}", header, records)).unwrap();
+    let data = load_data(path, None).unwrap();
+    assert_eq!(data.records.len(), 3);
+    assert!(!data.reordered);
+    assert_eq!(load_data(path, Some(2)).unwrap().records.len(), 2);
+
+    // newest first exports are sorted, keeping the first of duplicate records
+    let lines: Vec<_> = records.lines().collect();
+    let duplicate = lines[1].replace("11.0774", "99");
+    fs::write(
+        path,
+        format!(
+            "{}{}\n{}\n{}\n{}\n",
+            header, lines[2], lines[1], duplicate, lines[0]
+        ),
+    )
+    .unwrap();
+    let sorted = load_data(path, None).unwrap();
+    assert!(sorted.reordered);
+    assert_eq!(sorted.duplicate_count, 1);
+    let csv = |records: &[Record]| records.iter().map(Record::to_csv_str).collect::<Vec<_>>();
+    assert_eq!(csv(&sorted.records), csv(&data.records));
+    let log = MemoryWriter::new();
+    let server = ServerBuilder::new(path)
+        .with_http_port(0)
+        .with_log_writer(log.clone())
+        .build_and_start()
+        .unwrap();
+    server.shutdown();
+    assert!(log
+        .contents()
+        .contains("sorted them and dropped 1 duplicates"));
+    let records = data.records;
+
+    let replay = Replay::new(
+        "BTCUSD",
+        records,
+        1000,
+        ReplayMode::Once,
+        Arc::new(SimulatedClock::new(Utc::now())),
+    );
+    // records are re-encoded in the format of the data file
+    let mut line = String::new();
+    replay.encode(replay.current().unwrap(), &mut line);
+    assert_eq!(
+        line,
+        "1546300800000,2019-01-01 00:00:00,BTCUSD,3600,3603.6,3599,3602.6,53.3594"
+    );
+    assert_eq!(Record::from_csv_str(&line).unwrap().close, 3602.6);
+    let mut record = Record::from_csv_str(&line).unwrap();
+    record.timestamp = i64::MAX;
+    line.clear();
+    replay.encode(&record, &mut line);
+    assert!(line.starts_with(&format!("{},,BTCUSD,", i64::MAX)));
+
+    assert_eq!(
+        replay.record_at(1_546_300_799_999).map(|r| r.timestamp),
+        None
+    );
+    assert_eq!(
+        replay.record_at(1_546_300_860_000).map(|r| r.timestamp),
+        Some(1_546_300_860_000)
+    );
+    assert_eq!(
+        replay.record_at(1_546_300_970_000).map(|r| r.timestamp),
+        Some(1_546_300_860_000)
+    );
+    assert_eq!(
+        replay.record_at(i64::MAX).map(|r| r.timestamp),
+        Some(1_546_300_980_000)
+    );
+
+    // malformed input is an error rather than a panic
+    std::fs::write(
+        path,
+        format!(
+            "{}{}",
+            header, "1546300800000,2019-01-01 00:00:00,BTCUSD,3600.00,3603.60,3599.00,3602.60\n"
+        ),
+    )
+    .unwrap();
+    match load_data(path, None) {
+        Err(err @ ServerError::InvalidRecord(..)) => assert_eq!(
+            err.to_string(),
+            format!(
+                "invalid data file {}: line 2: expected 8 columns, found 7",
+                path
+            )
+        ),
+        result => panic!("unexpected result: {:?}", result),
+    }
+    std::fs::write(path, header).unwrap();
+    assert!(matches!(
+        load_data(path, None),
+        Err(ServerError::NoRecords(_))
+    ));
+
+    let result = ServerBuilder::new("missing.csv")
+        .with_http_port(0)
+        .with_log_writer(MemoryWriter::new())
+        .build_and_start();
+    assert!(matches!(result, Err(ServerError::Data(..))));
+    fs::remove_file(path).unwrap();
+}
+
+/// Runs the server binary, which exits after printing its config.
+fn print_config(args: &[&str]) -> String {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_server"))
+        .args(args)
+        .arg("--print-config")
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn config_test() {
+    let path = std::env::temp_dir().join(format!("config_test_{}.toml", std::process::id()));
+    let path = path.to_str().unwrap();
+    let write_config = |content: &str| fs::write(path, content).unwrap();
+
+    // missing values take their defaults
+    write_config(
+        "http_port = 8080
+replay_mode = 'loop'
+
+[[instruments]]
+symbol = 'ETHUSD'
+data_file = 'eth.csv'
+
+[logging]
+level = 'warning'
+",
+    );
+    let config = ServerConfig::from_file(path).unwrap();
+    assert!(config.validate().is_ok());
+    assert_eq!(config.http_port, 8080);
+    assert_eq!(config.replay_mode, ReplayMode::Loop);
+    assert_eq!(config.period, 1000);
+    assert_eq!(config.push_port, 0);
+    assert_eq!(config.bind_address, IpAddr::V4(Ipv4Addr::LOCALHOST));
+    assert_eq!(config.instruments.len(), 1);
+    assert_eq!(config.instruments[0].symbol, "ETHUSD");
+    assert_eq!(config.instruments[0].max_records_amount, None);
+    assert_eq!(config.logging.level, LogLevel::Warning);
+    assert_eq!(config.logging.path, "server_log.csv");
+    assert!(config.logging.rotation.is_none());
+
+    for (content, field) in &[
+        ("period = 0", "period"),
+        // less than a μs for each of the 10 subscribers
+        ("period = 5", "period"),
+        ("instruments = []", "instruments"),
+        ("[logging.rotation]\nkept_files = 2", "logging.rotation"),
+        ("[logging.rotation]\nmax_size = 0", "logging.rotation"),
+    ] {
+        write_config(content);
+        match ServerConfig::from_file(path).unwrap().validate() {
+            Err(ConfigError::Invalid(invalid_field, _)) => assert_eq!(&invalid_field, field),
+            result => panic!("{}: unexpected result {:?}", content, result),
+        }
+    }
+    write_config("periode = 10");
+    assert!(matches!(
+        ServerConfig::from_file(path),
+        Err(ConfigError::Parse(..))
+    ));
+    assert!(matches!(
+        ServerConfig::from_file("missing.toml"),
+        Err(ConfigError::Io(..))
+    ));
+
+    // flags override the file
+    write_config(
+        "http_port = 8080
+period = 5000
+
+[[instruments]]
+symbol = 'ETHUSD'
+data_file = 'eth.csv'
+",
+    );
+    let printed = print_config(&["--config", path, "--period", "10", "--symbol", "LTCUSD"]);
+    let config: ServerConfig = toml::from_str(&printed).unwrap();
+    assert_eq!(config.http_port, 8080);
+    assert_eq!(config.period, 10);
+    assert_eq!(config.instruments[0].symbol, "LTCUSD");
+    assert_eq!(config.instruments[0].data_file, "eth.csv");
+    write_config("[logging]\nmode = 'append'");
+    let mode = |args: &[&str]| {
+        let printed = print_config(&[&["--config", path], args].concat());
+        toml::from_str::<ServerConfig>(&printed)
+            .unwrap()
+            .logging
+            .mode
+    };
+    assert_eq!(mode(&[]), WriteMode::Append);
+    assert_eq!(mode(&["--log-append=false"]), WriteMode::Truncate);
+    write_config("");
+    assert_eq!(mode(&["--log-append"]), WriteMode::Append);
+    assert_eq!(mode(&["--log-append=true"]), WriteMode::Append);
+    write_config(&printed);
+
+    // the printed config reads back as the same config
+    assert_eq!(config.to_toml_string(), printed);
+    assert_eq!(print_config(&["--config", path]), printed);
+    fs::remove_file(path).unwrap();
+}